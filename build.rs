@@ -0,0 +1,26 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Versions of the migrations in `dir`, oldest first
+fn migration_versions(dir: &str) -> Vec<String> {
+    println!("cargo:rerun-if-changed={}", dir);
+    let mut versions = fs::read_dir(dir).expect("Failed to read migrations directory")
+        .map(|entry| entry.expect("Failed to read migrations directory").file_name().into_string()
+            .expect("Migration names must be valid UTF-8"))
+        .filter(|name| !name.starts_with('.'))
+        // Same derivation as diesel: the part before the first underscore, without dashes
+        .map(|name| name.split('_').next().unwrap().replace('-', ""))
+        .collect::<Vec<_>>();
+    versions.sort();
+    versions
+}
+
+// Records the version of every embedded migration, so the server can tell
+// which versions it knows about without the directories being present at runtime
+fn main() {
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("migration_versions.rs");
+    fs::write(out, format!("pub const POSTGRES_VERSIONS: &[&str] = &{:?};\npub const SQLITE_VERSIONS: &[&str] = &{:?};\n",
+                           migration_versions("migrations"), migration_versions("migrations_sqlite")))
+        .expect("Failed to write migration versions");
+}
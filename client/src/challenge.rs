@@ -0,0 +1,28 @@
+use beacon_protocol::v1::session::Challenge;
+use ring::digest;
+
+// Finds a solution to a proof-of-work challenge, for the request `binding` identifies
+// (see Challenge). This runs on the calling thread, which at the difficulties servers
+// use takes well under a second.
+pub fn solve(challenge: &Challenge, binding: &[u8]) -> Vec<u8> {
+    let difficulty = challenge.difficulty as u32;
+    (0u64..).map(|n| n.to_be_bytes())
+        .find(|solution| {
+            let mut ctx = digest::Context::new(&digest::SHA256);
+            ctx.update(&challenge.challenge);
+            ctx.update(binding);
+            ctx.update(solution);
+            leading_zero_bits(ctx.finish().as_ref()) >= difficulty
+        })
+        .expect("Every difficulty has a solution")
+        .to_vec()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 { break; }
+    }
+    bits
+}
@@ -0,0 +1,47 @@
+use beacon_protocol::v1::error::{ApiError, ErrorBody};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ClientError {
+    // The request never reached the server, so is always safe to retry
+    Connect(String),
+    // The request may or may not have been processed
+    Transport(String),
+    // The server rejected the request. `body` is None if the response wasn't an
+    // API error, e.g. one from a proxy.
+    Server { status: u16, body: Option<ErrorBody> },
+    // A response didn't have the expected shape
+    Decode(String),
+    // Authenticated requests need a registered device
+    NotRegistered,
+    // A package's signed prekey wasn't signed by its identity key
+    BadSignature,
+    // A Signer couldn't sign, e.g. because it had no randomness
+    Signing,
+}
+
+impl ClientError {
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            ClientError::Server { body: Some(body), .. } => Some(&body.error),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Connect(e) => write!(f, "could not connect: {}", e),
+            ClientError::Transport(e) => write!(f, "request failed: {}", e),
+            ClientError::Server { status, body: Some(body) } => write!(f, "server returned {}: {:?}", status, body.error),
+            ClientError::Server { status, body: None } => write!(f, "server returned {}", status),
+            ClientError::Decode(e) => write!(f, "unexpected response: {}", e),
+            ClientError::NotRegistered => write!(f, "no device is registered"),
+            ClientError::BadSignature => write!(f, "signed prekey does not match the identity key"),
+            ClientError::Signing => write!(f, "the key failed to sign"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
@@ -0,0 +1,402 @@
+// A typed async client for the Beacon API. It holds the session nonce, signing each
+// authenticated request with the device key and switching to the nonce in the response.
+mod challenge;
+mod error;
+mod signer;
+
+pub use beacon_protocol as protocol;
+pub use crate::challenge::solve as solve_challenge;
+pub use crate::error::ClientError;
+pub use crate::signer::Signer;
+pub use awc::http::Method;
+
+use awc::error::SendRequestError;
+use awc::http::StatusCode;
+use beacon_protocol::v1::PREFIX;
+use beacon_protocol::v1::attachment::{AttachmentLimits, UploadedAttachment, NewUpload, UploadProgress, FinalizeUpload};
+use beacon_protocol::v1::error::ApiError;
+use beacon_protocol::v1::message::{SendMessageRequest, MailboxReturn, CheckMessagesResponse, MarkRead, RECIPIENT_HEADER, MESSAGE_TYPE_HEADER};
+use beacon_protocol::v1::session::{Challenge, RegisterDeviceRequest, RegisterDeviceResponse, PushRegistration, DEVICE_ID_HEADER, NONCE_HEADER,
+                               SIGNED_NONCE_HEADER, NEW_NONCE_HEADER, CHALLENGE_HEADER, CHALLENGE_SOLUTION_HEADER};
+use beacon_protocol::v1::user::{UserCreation, CreateUserResponse, PreKeyUpdate, OTKAdd, ChatPackage, UserSettings, IdentityReset};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use uuid::Uuid;
+
+const JSON: &str = "application/json";
+const OCTET_STREAM: &str = "application/octet-stream";
+// Larger than any attachment a server would accept
+const MAX_RESPONSE_SIZE: usize = 256 * 1024 * 1024;
+// One-time keys sent per request by upload_onetime_keys
+const ONETIME_KEY_BATCH: usize = 100;
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    // Including the first
+    pub attempts: u32,
+    // Doubled after each attempt, unless the server says how long to wait
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { attempts: 3, backoff: Duration::from_millis(500) }
+    }
+}
+
+// A registered device, and the key it signs nonces with
+struct Device {
+    id: Uuid,
+    key: Box<dyn Signer>,
+}
+
+struct Request {
+    method: Method,
+    path: String,
+    headers: Vec<(&'static str, String)>,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn new(method: Method, path: impl Into<String>) -> Self {
+        Request { method, path: path.into(), headers: Vec::new(), content_type: JSON, body: Vec::new() }
+    }
+
+    fn post(path: impl Into<String>) -> Self {
+        Request::new(Method::POST, path)
+    }
+
+    fn headers(mut self, headers: Vec<(&'static str, String)>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    fn json<T: Serialize>(mut self, body: &T) -> Self {
+        self.body = serde_json::to_vec(body).expect("Request bodies always serialize");
+        self
+    }
+
+    fn bytes(mut self, body: &[u8]) -> Self {
+        self.content_type = OCTET_STREAM;
+        self.body = body.to_vec();
+        self
+    }
+}
+
+struct Response {
+    status: StatusCode,
+    new_nonce: Option<Vec<u8>>,
+    retry_after: Option<Duration>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error(&self) -> ClientError {
+        ClientError::Server {
+            status: self.status.as_u16(),
+            body: serde_json::from_slice(&self.body).ok(),
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(body: &[u8]) -> Result<T, ClientError> {
+    serde_json::from_slice(body).map_err(|e| ClientError::Decode(e.to_string()))
+}
+
+// Rate limits and unavailability pass, and a lost session is replaced with a new one
+fn retryable(error: &ClientError) -> bool {
+    match error {
+        ClientError::Server { status: 429, .. } | ClientError::Server { status: 503, .. } => true,
+        _ => matches!(error.api_error(), Some(ApiError::SessionInvalid) | Some(ApiError::SessionExpired))
+    }
+}
+
+pub struct Client {
+    http: awc::Client,
+    base_url: String,
+    retry: RetryPolicy,
+    device: Option<Device>,
+    // The nonce to sign next, unless the session has been lost
+    nonce: Option<Vec<u8>>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Client {
+            http: awc::Client::default(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            retry: RetryPolicy::default(),
+            device: None,
+            nonce: None,
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Acts as a device registered earlier
+    pub fn with_device(mut self, device_id: Uuid, key: impl Signer + 'static) -> Self {
+        self.device = Some(Device { id: device_id, key: Box::new(key) });
+        self
+    }
+
+    pub fn device_id(&self) -> Option<Uuid> {
+        self.device.as_ref().map(|device| device.id)
+    }
+
+    async fn send(&self, req: &Request, auth: &[(&'static str, String)]) -> Result<Response, ClientError> {
+        let mut builder = self.http.request(req.method.clone(), format!("{}{}{}", self.base_url, PREFIX, req.path))
+            .content_type(req.content_type);
+        for (name, value) in req.headers.iter().chain(auth) {
+            builder = builder.header(*name, value.as_str());
+        }
+        let mut res = builder.send_body(req.body.clone()).await.map_err(|e| match e {
+            SendRequestError::Connect(e) => ClientError::Connect(e.to_string()),
+            e => ClientError::Transport(e.to_string()),
+        })?;
+
+        let header = |name: &str| res.headers().get(name).and_then(|value| value.to_str().ok());
+        let new_nonce = header(NEW_NONCE_HEADER).and_then(|nonce| base64::decode(nonce).ok());
+        let retry_after = header("retry-after").and_then(|secs| secs.parse().ok()).map(Duration::from_secs);
+        let body = res.body().limit(MAX_RESPONSE_SIZE).await
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+        Ok(Response { status: res.status(), new_nonce, retry_after, body: body.to_vec() })
+    }
+
+    // A nonce from a new session. Sessions aren't tied to a device, so this also
+    // recovers from a lost nonce.
+    async fn open_session(&mut self) -> Result<Vec<u8>, ClientError> {
+        let res = self.send(&Request::post("/session/new"), &[]).await?;
+        match (res.status.is_success(), res.new_nonce.clone()) {
+            (true, Some(nonce)) => Ok(nonce),
+            (true, None) => Err(ClientError::Decode("no nonce in /session/new response".to_string())),
+            (false, _) => Err(res.error()),
+        }
+    }
+
+    // Signs the current nonce, which is used up whether or not the request succeeds
+    async fn auth_headers(&mut self) -> Result<Vec<(&'static str, String)>, ClientError> {
+        if self.device.is_none() {
+            return Err(ClientError::NotRegistered);
+        }
+        let nonce = match self.nonce.take() {
+            Some(nonce) => nonce,
+            None => self.open_session().await?,
+        };
+        let device = self.device.as_ref().ok_or(ClientError::NotRegistered)?;
+        Ok(vec![
+            (DEVICE_ID_HEADER, device.id.to_string()),
+            (NONCE_HEADER, base64::encode(&nonce)),
+            (SIGNED_NONCE_HEADER, base64::encode(&device.key.sign(&nonce)?)),
+        ])
+    }
+
+    // Sends a request, retrying while that is safe, and returns the response body.
+    // Only failures to connect are retried at the transport level, since anything
+    // later may already have been processed.
+    async fn execute(&mut self, req: Request, authenticated: bool) -> Result<Vec<u8>, ClientError> {
+        let mut attempt = 0;
+        let mut backoff = self.retry.backoff;
+        loop {
+            attempt += 1;
+            let auth = match authenticated {
+                true => self.auth_headers().await?,
+                false => Vec::new(),
+            };
+            let res = self.send(&req, &auth).await;
+            if let Ok(Response { new_nonce: Some(nonce), .. }) = &res {
+                self.nonce = Some(nonce.clone());
+            }
+
+            let wait = match res {
+                Ok(res) if res.status.is_success() => return Ok(res.body),
+                Ok(res) => {
+                    let error = res.error();
+                    if !retryable(&error) || attempt >= self.retry.attempts {
+                        return Err(error);
+                    }
+                    res.retry_after.unwrap_or(backoff)
+                },
+                Err(ClientError::Connect(_)) if attempt < self.retry.attempts => backoff,
+                Err(e) => return Err(e),
+            };
+            actix_rt::time::delay_for(wait).await;
+            backoff *= 2;
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&mut self, req: Request) -> Result<T, ClientError> {
+        decode(&self.execute(req, true).await?)
+    }
+
+    async fn call_empty(&mut self, req: Request) -> Result<(), ClientError> {
+        self.execute(req, true).await.map(|_body| ())
+    }
+
+    // An authenticated request without a body, for routes this SDK has no method for,
+    // e.g. ones added by a newer server. Returns the response body.
+    pub async fn request(&mut self, method: Method, path: &str) -> Result<Vec<u8>, ClientError> {
+        self.execute(Request::new(method, path), true).await
+    }
+
+    pub async fn challenge(&mut self) -> Result<Challenge, ClientError> {
+        decode(&self.execute(Request::post("/challenge/new"), false).await?)
+    }
+
+    // Headers proving work on a new challenge for `binding`, when the server requires them
+    async fn challenge_headers(&mut self, binding: &[u8]) -> Result<Vec<(&'static str, String)>, ClientError> {
+        let challenge = self.challenge().await?;
+        if challenge.difficulty == 0 {
+            return Ok(Vec::new());
+        }
+        let solution = challenge::solve(&challenge, binding);
+        Ok(vec![
+            (CHALLENGE_HEADER, base64::encode(&challenge.challenge)),
+            (CHALLENGE_SOLUTION_HEADER, base64::encode(&solution)),
+        ])
+    }
+
+    // Registers `key` as a new device, which then signs every authenticated request
+    pub async fn register_device(&mut self, key: impl Signer + 'static) -> Result<Uuid, ClientError> {
+        let nonce = self.open_session().await?;
+        let challenge = self.challenge_headers(&nonce).await?;
+        let request = RegisterDeviceRequest {
+            public_key: key.public_key(),
+            algorithm: key.algorithm(),
+            signed_nonce: key.sign(&nonce)?,
+            nonce,
+        };
+        let body = self.execute(Request::post("/devices/new").headers(challenge).json(&request), false).await?;
+        let RegisterDeviceResponse { device_id } = decode(&body)?;
+        self.device = Some(Device { id: device_id, key: Box::new(key) });
+        Ok(device_id)
+    }
+
+    // Creates a user owning this device, signing `signed_prekey` with the identity key
+    pub async fn create_user(&mut self, email: &str, identity_key: &dyn Signer, signed_prekey: &[u8]) -> Result<Uuid, ClientError> {
+        let challenge = self.challenge_headers(email.as_bytes()).await?;
+        let request = UserCreation {
+            email: email.to_string(),
+            identity_key: identity_key.public_key(),
+            identity_key_algorithm: identity_key.algorithm(),
+            signed_prekey: signed_prekey.to_vec(),
+            prekey_signature: identity_key.sign(signed_prekey)?,
+            nickname: None,
+            bio: None,
+        };
+        let CreateUserResponse { user_id } = self.call(Request::post("/users/new").headers(challenge).json(&request)).await?;
+        Ok(user_id)
+    }
+
+    // Wakes this device through the named push provider when messages arrive for it
+    pub async fn register_push(&mut self, provider: &str, token: &str) -> Result<(), ClientError> {
+        let request = PushRegistration { provider: provider.to_string(), token: token.to_string() };
+        self.call_empty(Request::post("/devices/push").json(&request)).await
+    }
+
+    pub async fn unregister_push(&mut self) -> Result<(), ClientError> {
+        self.call_empty(Request::new(Method::DELETE, "/devices/push")).await
+    }
+
+    pub async fn update_settings(&mut self, settings: &UserSettings) -> Result<(), ClientError> {
+        self.call_empty(Request::post("/users/settings").json(settings)).await
+    }
+
+    // Replaces the user's identity key, along with a prekey signed by the new one
+    pub async fn reset_identity(&mut self, identity_key: &dyn Signer, signed_prekey: &[u8]) -> Result<(), ClientError> {
+        let request = IdentityReset {
+            identity_key: identity_key.public_key(),
+            identity_key_algorithm: identity_key.algorithm(),
+            signed_prekey: signed_prekey.to_vec(),
+            prekey_signature: identity_key.sign(signed_prekey)?,
+        };
+        self.call_empty(Request::post("/users/identity").json(&request)).await
+    }
+
+    pub async fn rotate_signed_prekey(&mut self, identity_key: &dyn Signer, signed_prekey: &[u8]) -> Result<(), ClientError> {
+        let request = PreKeyUpdate {
+            signed_prekey: signed_prekey.to_vec(),
+            prekey_signature: identity_key.sign(signed_prekey)?,
+        };
+        self.call_empty(Request::post("/keys/signed").json(&request)).await
+    }
+
+    // Uploads one-time prekeys, in batches small enough for any request size limit
+    pub async fn upload_onetime_keys(&mut self, keys: &[Vec<u8>]) -> Result<(), ClientError> {
+        for batch in keys.chunks(ONETIME_KEY_BATCH) {
+            let request = OTKAdd { keys: batch.iter().map(base64::encode).collect() };
+            self.call_empty(Request::post("/keys/onetime").json(&request)).await?;
+        }
+        Ok(())
+    }
+
+    // Fetches the keys needed to start a conversation with a user, using up one of their
+    // one-time keys. Fails unless the prekey was signed by the identity key.
+    pub async fn fetch_package(&mut self, user_id: Uuid) -> Result<ChatPackage, ClientError> {
+        let package: ChatPackage = self.call(Request::post(format!("/users/{}/package", user_id))).await?;
+        package.identity_key_algorithm.verify(&package.identity_key, &package.signed_prekey, &package.prekey_signature)
+            .map_err(|_e| ClientError::BadSignature)?;
+        Ok(package)
+    }
+
+    pub async fn send_message(&mut self, message: &SendMessageRequest) -> Result<(), ClientError> {
+        self.call_empty(Request::post("/messages/send").json(message)).await
+    }
+
+    // Sends a raw ciphertext, without base64 encoding it into a JSON payload
+    pub async fn send_ciphertext(&mut self, recipient: Uuid, message_type: &str, ciphertext: &[u8]) -> Result<(), ClientError> {
+        let req = Request::post("/messages/send")
+            .headers(vec![
+                (RECIPIENT_HEADER, recipient.to_string()),
+                (MESSAGE_TYPE_HEADER, message_type.to_string()),
+            ])
+            .bytes(ciphertext);
+        self.call_empty(req).await
+    }
+
+    // Takes every message waiting for this device
+    pub async fn check_mailbox(&mut self) -> Result<Vec<MailboxReturn>, ClientError> {
+        let CheckMessagesResponse { messages } = self.call(Request::post("/messages/mailbox")).await?;
+        Ok(messages)
+    }
+
+    pub async fn mark_read(&mut self, message_ids: Vec<Uuid>) -> Result<(), ClientError> {
+        self.call_empty(Request::post("/messages/read").json(&MarkRead { message_ids })).await
+    }
+
+    pub async fn attachment_limits(&mut self) -> Result<AttachmentLimits, ClientError> {
+        self.call(Request::post("/attachments/limits")).await
+    }
+
+    pub async fn upload_attachment(&mut self, data: &[u8]) -> Result<UploadedAttachment, ClientError> {
+        self.call(Request::post("/attachments/new").bytes(data)).await
+    }
+
+    pub async fn download_attachment(&mut self, attachment_id: Uuid) -> Result<Vec<u8>, ClientError> {
+        self.execute(Request::post(format!("/attachments/{}", attachment_id)), true).await
+    }
+
+    pub async fn create_upload(&mut self, size: i64) -> Result<UploadProgress, ClientError> {
+        self.call(Request::post("/attachments/uploads/new").json(&NewUpload { size })).await
+    }
+
+    // Chunks must be sent in order, starting from the `received` offset of the upload
+    pub async fn upload_chunk(&mut self, upload_id: Uuid, offset: i64, data: &[u8]) -> Result<UploadProgress, ClientError> {
+        let path = format!("/attachments/uploads/{}?offset={}", upload_id, offset);
+        self.call(Request::new(Method::PUT, path).bytes(data)).await
+    }
+
+    pub async fn upload_progress(&mut self, upload_id: Uuid) -> Result<UploadProgress, ClientError> {
+        self.call(Request::post(format!("/attachments/uploads/{}", upload_id))).await
+    }
+
+    // `digest` is the SHA-256 of the complete blob
+    pub async fn finalize_upload(&mut self, upload_id: Uuid, digest: &[u8]) -> Result<UploadedAttachment, ClientError> {
+        let request = FinalizeUpload { digest: digest.to_vec() };
+        self.call(Request::post(format!("/attachments/uploads/{}/finalize", upload_id)).json(&request)).await
+    }
+}
@@ -0,0 +1,47 @@
+use beacon_protocol::crypto::KeyAlgorithm;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+
+use crate::error::ClientError;
+
+// A private key the client signs with: device keys sign session nonces, and identity
+// keys sign prekeys. Keys ring can't hold, e.g. ones kept in a platform keystore,
+// can implement this themselves.
+pub trait Signer {
+    fn algorithm(&self) -> KeyAlgorithm;
+    // Encoded as the server expects for the algorithm
+    fn public_key(&self) -> Vec<u8>;
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, ClientError>;
+}
+
+impl Signer for Ed25519KeyPair {
+    fn algorithm(&self) -> KeyAlgorithm {
+        KeyAlgorithm::Ed25519
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        KeyPair::public_key(self).as_ref().to_vec()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, ClientError> {
+        Ok(Ed25519KeyPair::sign(self, message).as_ref().to_vec())
+    }
+}
+
+// The key pair must be for ECDSA_P256_SHA256_ASN1_SIGNING, the only curve and
+// signature encoding the server accepts
+impl Signer for EcdsaKeyPair {
+    fn algorithm(&self) -> KeyAlgorithm {
+        KeyAlgorithm::EcdsaP256
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        KeyPair::public_key(self).as_ref().to_vec()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, ClientError> {
+        EcdsaKeyPair::sign(self, &SystemRandom::new(), message)
+            .map(|signature| signature.as_ref().to_vec())
+            .map_err(|_e| ClientError::Signing)
+    }
+}
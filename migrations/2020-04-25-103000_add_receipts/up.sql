@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN read_receipts bool NOT NULL DEFAULT true;
+ALTER TABLE messages ADD COLUMN delivered_at timestamptz;
\ No newline at end of file
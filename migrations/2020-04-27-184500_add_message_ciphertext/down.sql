@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM mailbox WHERE message_id IN (SELECT id FROM messages WHERE payload IS NULL);
+DELETE FROM messages WHERE payload IS NULL;
+ALTER TABLE messages DROP CONSTRAINT messages_has_body;
+ALTER TABLE messages ALTER COLUMN payload SET NOT NULL;
+ALTER TABLE messages DROP COLUMN ciphertext;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE messages ADD COLUMN ciphertext bytea;
+ALTER TABLE messages ALTER COLUMN payload DROP NOT NULL;
+ALTER TABLE messages ADD CONSTRAINT messages_has_body CHECK (payload IS NOT NULL OR ciphertext IS NOT NULL);
\ No newline at end of file
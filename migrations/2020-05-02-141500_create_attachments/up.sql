@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE attachments (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    uploader uuid NOT NULL references users,
+    size bigint NOT NULL,
+    created timestamptz NOT NULL DEFAULT now(),
+    expires timestamptz NOT NULL
+)
\ No newline at end of file
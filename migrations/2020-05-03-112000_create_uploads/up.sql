@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE uploads (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    uploader uuid NOT NULL references users,
+    size bigint NOT NULL,
+    received bigint NOT NULL DEFAULT 0,
+    updated timestamptz NOT NULL DEFAULT now()
+)
\ No newline at end of file
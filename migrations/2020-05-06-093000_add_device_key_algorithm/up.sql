@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE devices ADD COLUMN key_algorithm text NOT NULL DEFAULT 'ed25519';
\ No newline at end of file
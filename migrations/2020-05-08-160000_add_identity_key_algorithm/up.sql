@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN identity_key_algorithm text NOT NULL DEFAULT 'ed25519';
\ No newline at end of file
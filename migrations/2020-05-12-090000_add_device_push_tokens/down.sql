@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE devices DROP COLUMN push_token;
+ALTER TABLE devices DROP COLUMN push_provider;
\ No newline at end of file
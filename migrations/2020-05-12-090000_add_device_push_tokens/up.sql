@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE devices ADD COLUMN push_provider text;
+ALTER TABLE devices ADD COLUMN push_token text;
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE message_attachments (
+    message_id uuid NOT NULL REFERENCES messages ON DELETE CASCADE,
+    attachment_id uuid NOT NULL REFERENCES attachments ON DELETE CASCADE,
+    PRIMARY KEY (message_id, attachment_id)
+);
+CREATE INDEX message_attachments_attachment_id ON message_attachments (attachment_id);
\ No newline at end of file
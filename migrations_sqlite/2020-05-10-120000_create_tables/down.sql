@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE uploads;
+DROP TABLE attachments;
+DROP TABLE mailbox;
+DROP TABLE messages;
+DROP TABLE onetimekeys;
+DROP TABLE sessions;
+DROP TABLE devices;
+DROP TABLE users;
\ No newline at end of file
@@ -0,0 +1,64 @@
+-- Your SQL goes here
+-- The schema of migrations/ up to 2020-05-08, in SQLite's types: uuids are
+-- 16 byte blobs generated by the server, and times are UTC without an offset
+CREATE TABLE users (
+    id blob PRIMARY KEY NOT NULL,
+    identity_key blob NOT NULL,
+    signed_prekey blob NOT NULL,
+    prekey_signature blob NOT NULL,
+    bio text,
+    profile_thumb blob,
+    email text UNIQUE NOT NULL,
+    email_verified boolean NOT NULL DEFAULT false,
+    nickname text,
+    last_seen timestamp,
+    read_receipts boolean NOT NULL DEFAULT true,
+    identity_key_algorithm text NOT NULL DEFAULT 'ed25519'
+);
+CREATE TABLE devices (
+    id blob PRIMARY KEY NOT NULL,
+    user_id blob REFERENCES users,
+    missed_messages integer NOT NULL DEFAULT 0,
+    public_key blob NOT NULL,
+    key_algorithm text NOT NULL DEFAULT 'ed25519'
+);
+CREATE TABLE sessions (
+    id integer PRIMARY KEY NOT NULL,
+    nonce blob NOT NULL UNIQUE,
+    expires timestamp NOT NULL
+);
+CREATE TABLE onetimekeys (
+    id integer PRIMARY KEY NOT NULL,
+    user_id blob REFERENCES users,
+    prekey blob NOT NULL
+);
+CREATE TABLE messages (
+    id blob PRIMARY KEY NOT NULL,
+    recipient blob NOT NULL REFERENCES users,
+    sender blob NOT NULL REFERENCES users,
+    reception_time timestamp NOT NULL,
+    message_type text NOT NULL,
+    payload text,
+    delivered_at timestamp,
+    ciphertext blob,
+    CHECK (payload IS NOT NULL OR ciphertext IS NOT NULL)
+);
+CREATE TABLE mailbox (
+    id integer PRIMARY KEY NOT NULL,
+    device_id blob NOT NULL REFERENCES devices,
+    message_id blob NOT NULL REFERENCES messages
+);
+CREATE TABLE attachments (
+    id blob PRIMARY KEY NOT NULL,
+    uploader blob NOT NULL REFERENCES users,
+    size bigint NOT NULL,
+    created timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires timestamp NOT NULL
+);
+CREATE TABLE uploads (
+    id blob PRIMARY KEY NOT NULL,
+    uploader blob NOT NULL REFERENCES users,
+    size bigint NOT NULL,
+    received bigint NOT NULL DEFAULT 0,
+    updated timestamp NOT NULL
+);
\ No newline at end of file
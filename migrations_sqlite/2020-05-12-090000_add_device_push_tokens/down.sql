@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+-- The bundled SQLite can't drop columns, so the table is rebuilt without them
+CREATE TABLE devices_without_push (
+    id blob PRIMARY KEY NOT NULL,
+    user_id blob REFERENCES users,
+    missed_messages integer NOT NULL DEFAULT 0,
+    public_key blob NOT NULL,
+    key_algorithm text NOT NULL DEFAULT 'ed25519'
+);
+INSERT INTO devices_without_push SELECT id, user_id, missed_messages, public_key, key_algorithm FROM devices;
+DROP TABLE devices;
+ALTER TABLE devices_without_push RENAME TO devices;
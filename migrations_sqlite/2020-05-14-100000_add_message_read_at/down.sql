@@ -0,0 +1,16 @@
+-- This file should undo anything in `up.sql`
+-- The bundled SQLite can't drop columns, so the table is rebuilt without it
+CREATE TABLE messages_without_read_at (
+    id blob PRIMARY KEY NOT NULL,
+    recipient blob NOT NULL REFERENCES users,
+    sender blob NOT NULL REFERENCES users,
+    reception_time timestamp NOT NULL,
+    message_type text NOT NULL,
+    payload text,
+    delivered_at timestamp,
+    ciphertext blob,
+    CHECK (payload IS NOT NULL OR ciphertext IS NOT NULL)
+);
+INSERT INTO messages_without_read_at SELECT id, recipient, sender, reception_time, message_type, payload, delivered_at, ciphertext FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_without_read_at RENAME TO messages;
\ No newline at end of file
@@ -0,0 +1,181 @@
+use serde::{Serializer, de, Deserializer};
+use std::cell::Cell;
+use std::fmt;
+
+thread_local! {
+    static RAW_BYTES: Cell<bool> = const { Cell::new(false) };
+}
+
+// Runs `f` with byte fields serialised as raw byte strings rather than base64. rmp-serde
+// claims to be human readable like JSON, so MessagePack encoding has to ask for this itself.
+pub fn raw_bytes<T>(f: impl FnOnce() -> T) -> T {
+    struct Reset(bool);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            RAW_BYTES.with(|raw| raw.set(self.0));
+        }
+    }
+
+    let _reset = Reset(RAW_BYTES.with(|raw| raw.replace(true)));
+    f()
+}
+
+// Base64 in JSON, raw bytes in MessagePack when encoded within `raw_bytes`
+pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    if RAW_BYTES.with(Cell::get) {
+        return serializer.serialize_bytes(bytes);
+    }
+    serializer.serialize_str(&base64::encode(bytes))
+
+    // Could also use a wrapper type with a Display implementation to avoid
+    // allocating the String.
+    //
+    // serializer.collect_str(&Base64(bytes))
+}
+
+// Either form is accepted whatever the format, since formats with byte strings also have strings
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserializer.deserialize_any(BytesVisitor)
+}
+
+struct BytesVisitor;
+
+impl<'de> de::Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a base64 string or byte string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+        base64::decode(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+}
+
+
+pub mod option {
+    use serde::{Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match bytes {
+            Some(b) => super::serialize(b, serializer),
+            None => serializer.serialize_none()
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+        where D: Deserializer<'de>
+    {
+        #[derive(Deserialize)]
+        #[serde(transparent)]
+        struct Wrapper(#[serde(with = "super")] Vec<u8>);
+
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(b)| b))
+    }
+}
+
+// Message payloads are kept as JSON, which has no byte strings. Any a binary format such as
+// MessagePack sends become base64 strings, just as a JSON client would have sent the bytes.
+pub mod payload {
+    use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+    use serde_json::{Map, Number, Value};
+    use std::fmt;
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Value>, D::Error>
+        where D: Deserializer<'de>
+    {
+        Ok(Option::<Payload>::deserialize(deserializer)?.map(|Payload(value)| value))
+    }
+
+    struct Payload(Value);
+
+    impl<'de> Deserialize<'de> for Payload {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where D: Deserializer<'de>
+        {
+            deserializer.deserialize_any(PayloadVisitor).map(Payload)
+        }
+    }
+
+    struct PayloadVisitor;
+
+    impl<'de> Visitor<'de> for PayloadVisitor {
+        type Value = Value;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a JSON value")
+        }
+
+        fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+            Ok(Value::Bool(v))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+            Ok(Value::Number(v.into()))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+            Ok(Value::Number(v.into()))
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+            Number::from_f64(v).map(Value::Number).ok_or_else(|| E::custom("payloads can't hold NaN or infinite numbers"))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+            Ok(Value::String(v.to_string()))
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+            Ok(Value::String(base64::encode(v)))
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Value, D::Error>
+            where D: Deserializer<'de>
+        {
+            Payload::deserialize(deserializer).map(|Payload(value)| value)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+            where A: SeqAccess<'de>
+        {
+            let mut values = Vec::new();
+            while let Some(Payload(value)) = seq.next_element()? {
+                values.push(value);
+            }
+            Ok(Value::Array(values))
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+            where A: MapAccess<'de>
+        {
+            let mut values = Map::new();
+            while let Some((key, Payload(value))) = map.next_entry::<String, Payload>()? {
+                values.insert(key, value);
+            }
+            Ok(Value::Object(values))
+        }
+    }
+}
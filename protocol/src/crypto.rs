@@ -0,0 +1,75 @@
+use ring::signature;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "diesel")]
+use diesel::sql_types::Text;
+
+// Signature algorithm of a stored public key. The tag is persisted alongside the key,
+// so supporting a new algorithm only needs a new variant here.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "diesel", derive(AsExpression, FromSqlRow), sql_type = "Text")]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    #[default]
+    Ed25519,
+    // Uncompressed SEC1 public keys and ASN.1 DER signatures, as produced by hardware keystores
+    EcdsaP256,
+}
+
+impl KeyAlgorithm {
+    pub fn tag(self) -> &'static str {
+        match self {
+            KeyAlgorithm::Ed25519 => "ed25519",
+            KeyAlgorithm::EcdsaP256 => "ecdsa-p256",
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "ed25519" => Some(KeyAlgorithm::Ed25519),
+            "ecdsa-p256" => Some(KeyAlgorithm::EcdsaP256),
+            _ => None
+        }
+    }
+
+    // Whether the key could possibly be valid for the algorithm
+    pub fn is_valid_public_key(self, public_key: &[u8]) -> bool {
+        match self {
+            KeyAlgorithm::Ed25519 => public_key.len() == 32,
+            KeyAlgorithm::EcdsaP256 => public_key.len() == 65 && public_key[0] == 0x04,
+        }
+    }
+
+    pub fn verify(self, public_key: &[u8], message: &[u8], sig: &[u8]) -> Result<(), ring::error::Unspecified> {
+        let algorithm: &'static dyn signature::VerificationAlgorithm = match self {
+            KeyAlgorithm::Ed25519 => &signature::ED25519,
+            KeyAlgorithm::EcdsaP256 => &signature::ECDSA_P256_SHA256_ASN1,
+        };
+        signature::UnparsedPublicKey::new(algorithm, public_key)
+            .verify(message, sig)
+    }
+}
+
+#[cfg(feature = "diesel")]
+mod sql {
+    use diesel::backend::Backend;
+    use diesel::deserialize::{self, FromSql};
+    use diesel::serialize::{self, Output, ToSql};
+    use diesel::sql_types::Text;
+    use std::io::Write;
+    use super::KeyAlgorithm;
+
+    impl<DB: Backend> ToSql<Text, DB> for KeyAlgorithm where str: ToSql<Text, DB> {
+        fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+            self.tag().to_sql(out)
+        }
+    }
+
+    impl<DB: Backend> FromSql<Text, DB> for KeyAlgorithm where String: FromSql<Text, DB> {
+        fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+            let tag = String::from_sql(bytes)?;
+            KeyAlgorithm::from_tag(&tag)
+                .ok_or_else(|| format!("Unknown key algorithm {}", tag).into())
+        }
+    }
+}
@@ -0,0 +1,12 @@
+// Request and response bodies of the Beacon HTTP API, shared by the server and clients.
+// Bodies live in a module per API version, so a breaking change is made in a new
+// version while servers keep serving the old one.
+// Diesel 1.4's derives put their impls inside constants, which newer compilers warn about
+#![allow(non_local_definitions)]
+#[cfg(feature = "diesel")]
+#[macro_use]
+extern crate diesel;
+
+pub mod base64enc;
+pub mod crypto;
+pub mod v1;
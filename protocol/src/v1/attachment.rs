@@ -0,0 +1,53 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::base64enc;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct AttachmentLimits {
+    // In bytes
+    pub max_size: usize,
+    // In seconds
+    pub retention: i64,
+    // Seconds a resumable upload may go without progress before it is discarded
+    pub upload_timeout: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UploadedAttachment {
+    pub attachment_id: Uuid,
+    pub expires: DateTime<Utc>,
+    pub limits: AttachmentLimits,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct NewUpload {
+    pub size: i64
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UploadProgress {
+    pub upload_id: Uuid,
+    pub size: i64,
+    pub received: i64,
+}
+
+// Query string of a chunk upload
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ChunkOffset {
+    pub offset: i64
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FinalizeUpload {
+    // SHA-256 of the complete encrypted blob
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub digest: Vec<u8>
+}
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::crypto::KeyAlgorithm;
+
+// Shape of every error response body
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ErrorBody {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<Uuid>,
+    #[serde(flatten)]
+    pub error: ApiError,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "type")]
+pub enum ApiError {
+    InsufficientPrekeys,
+    SessionExpired,
+    SessionInvalid,
+    UnknownEntity { #[serde(flatten)] entity: Entity },
+    RecordMustBeUnique { name: String },
+    AuthenticationError,
+    SignatureMismatch,
+    MalformedKey { algorithm: KeyAlgorithm },
+    MalformedHeader { name: String },
+    MalformedBody { error_message: String },
+    UnknownMessageType { name: String },
+    ReservedMessageType { name: String },
+    InvalidPayload { message_type: String, error_message: String },
+    PayloadTooLarge { limit: usize },
+    UploadOffsetMismatch { expected: i64 },
+    IncompleteUpload { size: i64, received: i64 },
+    DigestMismatch,
+    RateLimited { retry_after: u64 },
+    ChallengeRequired,
+    ChallengeFailed,
+    UnknownPushProvider { name: String },
+    // Details are only logged by the server
+    InternalError,
+    // An error added in a later server than this crate knows about
+    #[cfg_attr(feature = "schemars", schemars(skip))]
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "entity")]
+pub enum Entity {
+    User { uuid: Uuid },
+    Device { uuid: Uuid },
+    Attachment { uuid: Uuid },
+    Upload { uuid: Uuid },
+}
@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::base64enc;
+
+// Message type of receipts generated by the server
+pub const RECEIPT_TYPE: &str = "receipt";
+// Message type of notices about other users generated by the server
+pub const SYSTEM_TYPE: &str = "system";
+
+// A message with a JSON payload. Messages of type "ciphertext" may instead be sent as the
+// raw body of an application/octet-stream request, with the envelope in these headers.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SendMessageRequest {
+    pub recipient: Uuid,
+    #[serde(rename="type")]
+    pub message_type: String,
+    // Bytes are base64 strings, even when a MessagePack request sends them as binary,
+    // so recipients receive the same payload whichever encoding either side uses
+    #[cfg_attr(feature = "schemars", schemars(with = "Option<serde_json::Value>"))]
+    #[serde(default, deserialize_with = "base64enc::payload::deserialize")]
+    pub payload: Option<serde_json::Value>,
+}
+
+pub const RECIPIENT_HEADER: &str = "X-RECIPIENT";
+pub const MESSAGE_TYPE_HEADER: &str = "X-MESSAGE-TYPE";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+// Payload of a receipt message
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Receipt {
+    pub status: ReceiptStatus,
+    pub message_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct MailboxReturn {
+    pub id: Uuid,
+    pub sender: Uuid,
+    #[serde(rename="type")]
+    pub message_type: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+    #[cfg_attr(feature = "schemars", schemars(with = "Option<String>"))]
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base64enc::option")]
+    pub ciphertext: Option<Vec<u8>>
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CheckMessagesResponse {
+    pub messages: Vec<MailboxReturn>
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct MarkRead {
+    pub message_ids: Vec<Uuid>
+}
@@ -0,0 +1,8 @@
+pub mod session;
+pub mod user;
+pub mod message;
+pub mod attachment;
+pub mod error;
+
+// Every path in this version is relative to this prefix
+pub const PREFIX: &str = "/v1";
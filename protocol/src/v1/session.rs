@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::base64enc;
+use crate::crypto::KeyAlgorithm;
+
+// Authenticated requests carry the device, the session's current nonce and its signature
+// by the device key. Every response to one carries the nonce to sign next.
+pub const DEVICE_ID_HEADER: &str = "X-DEVICEID";
+pub const NONCE_HEADER: &str = "X-NONCE";
+pub const SIGNED_NONCE_HEADER: &str = "X-SIGNEDNONCE";
+pub const NEW_NONCE_HEADER: &str = "x-newnonce";
+
+// A solved proof-of-work challenge, required by registration when the server enables them
+pub const CHALLENGE_HEADER: &str = "X-CHALLENGE";
+pub const CHALLENGE_SOLUTION_HEADER: &str = "X-CHALLENGE-SOLUTION";
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Challenge {
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub challenge: Vec<u8>,
+    // Leading zero bits required of SHA-256(challenge | binding | solution). The binding is
+    // the session nonce when registering a device, and the email when creating a user.
+    pub difficulty: u8,
+    pub expires: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RegisterDeviceRequest {
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub public_key: Vec<u8>,
+    #[serde(default)]
+    pub algorithm: KeyAlgorithm,
+    // A nonce from /session/new, signed with the key being registered
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub nonce: Vec<u8>,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub signed_nonce: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RegisterDeviceResponse {
+    pub device_id: Uuid
+}
+
+// A token from a push service, through which the device can be woken when messages
+// arrive while it isn't polling. `provider` names one of those the server is configured with.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PushRegistration {
+    pub provider: String,
+    pub token: String,
+}
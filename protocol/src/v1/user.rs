@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::base64enc;
+use crate::crypto::KeyAlgorithm;
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UserCreation {
+    pub email: String,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub identity_key: Vec<u8>,
+    #[serde(default)]
+    pub identity_key_algorithm: KeyAlgorithm,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub signed_prekey: Vec<u8>,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub prekey_signature: Vec<u8>,
+    pub nickname: Option<String>,
+    pub bio: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CreateUserResponse {
+    pub user_id: Uuid
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PreKeyUpdate {
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub signed_prekey: Vec<u8>,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub prekey_signature: Vec<u8>,
+}
+
+// One-time prekeys, base64 encoded
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct OTKAdd {
+    pub keys: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ChatPackage {
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub identity_key: Vec<u8>,
+    pub identity_key_algorithm: KeyAlgorithm,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub signed_prekey: Vec<u8>,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub prekey_signature: Vec<u8>,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub onetime_key: Vec<u8>
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UserSettings {
+    pub read_receipts: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct IdentityReset {
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub identity_key: Vec<u8>,
+    #[serde(default)]
+    pub identity_key_algorithm: KeyAlgorithm,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub signed_prekey: Vec<u8>,
+    #[cfg_attr(feature = "schemars", schemars(with = "String"))]
+    #[serde(with = "base64enc")]
+    pub prekey_signature: Vec<u8>,
+}
@@ -0,0 +1,142 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use beacon_protocol::v1::attachment::{AttachmentLimits, UploadedAttachment, UploadProgress};
+use crate::blobstore::BlobStore;
+use crate::storage::{Storage, NewAttachment, UploadState};
+use crate::utils::{HandlerError, Entity};
+
+pub fn limits_from_env() -> AttachmentLimits {
+    AttachmentLimits {
+        max_size: dotenv::var("ATTACHMENT_MAX_SIZE")
+            .map(|s| s.parse().expect("ATTACHMENT_MAX_SIZE must be a number"))
+            .unwrap_or(25 * 1024 * 1024),
+        retention: dotenv::var("ATTACHMENT_RETENTION")
+            .map(|s| s.parse().expect("ATTACHMENT_RETENTION must be a number"))
+            .unwrap_or(30 * 24 * 60 * 60),
+        upload_timeout: dotenv::var("ATTACHMENT_UPLOAD_TIMEOUT")
+            .map(|s| s.parse().expect("ATTACHMENT_UPLOAD_TIMEOUT must be a number"))
+            .unwrap_or(24 * 60 * 60),
+    }
+}
+
+fn new_attachment(limits: &AttachmentLimits, uploader: Uuid, size: i64) -> NewAttachment {
+    NewAttachment {
+        id: Uuid::new_v4(),
+        uploader,
+        size,
+        expires: Utc::now() + Duration::seconds(limits.retention),
+    }
+}
+
+pub fn upload_attachment(storage: &dyn Storage, store: &dyn BlobStore, limits: &AttachmentLimits, uploader: Uuid, data: &[u8]) -> Result<UploadedAttachment, HandlerError> {
+    if data.len() > limits.max_size {
+        return Err(HandlerError::PayloadTooLarge { limit: limits.max_size });
+    }
+
+    // The attachment is only recorded once the blob has been stored
+    let attachment = new_attachment(limits, uploader, data.len() as i64);
+    store.put(attachment.id, data)?;
+    if let Err(e) = storage.insert_attachment(&attachment) {
+        store.delete(attachment.id)?;
+        return Err(e);
+    }
+
+    Ok(UploadedAttachment {
+        attachment_id: attachment.id,
+        expires: attachment.expires,
+        limits: limits.clone()
+    })
+}
+
+// Only the uploader and the recipients of messages referencing the attachment may download
+// it. Anyone else is told it doesn't exist, so ids can't be probed.
+pub fn download_attachment(storage: &dyn Storage, store: &dyn BlobStore, attachment_id: Uuid, user_id: Uuid) -> Result<Vec<u8>, HandlerError> {
+    let unknown = || HandlerError::UnknownEntity { entity: Entity::Attachment { uuid: attachment_id } };
+
+    if !storage.attachment_readable(attachment_id, user_id)? {
+        return Err(unknown());
+    }
+    store.get(attachment_id)?.ok_or_else(unknown)
+}
+
+// Removes attachments past their retention period, returning how many were removed
+pub fn expire_attachments(storage: &dyn Storage, store: &dyn BlobStore) -> Result<usize, HandlerError> {
+    let expired = storage.expire_attachments()?;
+
+    for attachment_id in &expired {
+        store.delete(*attachment_id)?;
+    }
+    Ok(expired.len())
+}
+
+fn progress(upload_id: Uuid, state: UploadState) -> UploadProgress {
+    UploadProgress { upload_id, size: state.size, received: state.received }
+}
+
+pub fn create_upload(storage: &dyn Storage, limits: &AttachmentLimits, uploader: Uuid, size: i64) -> Result<UploadProgress, HandlerError> {
+    if size < 0 || size as usize > limits.max_size {
+        return Err(HandlerError::PayloadTooLarge { limit: limits.max_size });
+    }
+
+    let upload_id = storage.create_upload(uploader, size)?;
+    Ok(UploadProgress { upload_id, size, received: 0 })
+}
+
+pub fn upload_progress(storage: &dyn Storage, upload_id: Uuid, uploader: Uuid) -> Result<UploadProgress, HandlerError> {
+    Ok(progress(upload_id, storage.upload_state(upload_id, uploader)?))
+}
+
+// Chunks must be sent in order, so a client resumes from the `received` offset
+pub fn upload_chunk(storage: &dyn Storage, store: &dyn BlobStore, upload_id: Uuid, uploader: Uuid, offset: i64, data: &[u8]) -> Result<UploadProgress, HandlerError> {
+    let state = storage.advance_upload(upload_id, uploader, &mut |state| {
+        if offset != state.received {
+            return Err(HandlerError::UploadOffsetMismatch { expected: state.received });
+        }
+        let received = state.received + data.len() as i64;
+        if received > state.size {
+            return Err(HandlerError::PayloadTooLarge { limit: state.size as usize });
+        }
+
+        store.write_partial(upload_id, offset as u64, data)?;
+        Ok(received)
+    })?;
+    Ok(progress(upload_id, state))
+}
+
+pub fn finalize_upload(storage: &dyn Storage, store: &dyn BlobStore, limits: &AttachmentLimits, upload_id: Uuid, uploader: Uuid, digest: &[u8]) -> Result<UploadedAttachment, HandlerError> {
+    // An upload's size is fixed when it is created, so can be read before locking it
+    let size = storage.upload_state(upload_id, uploader)?.size;
+    let attachment = new_attachment(limits, uploader, size);
+    let attachment_id = attachment.id;
+
+    storage.finish_upload(upload_id, uploader, &attachment, &mut |UploadState { size, received }| {
+        if received != size {
+            return Err(HandlerError::IncompleteUpload { size, received });
+        }
+
+        let data = store.read_partial(upload_id)?.unwrap_or_default();
+        let actual = ring::digest::digest(&ring::digest::SHA256, &data);
+        ring::constant_time::verify_slices_are_equal(actual.as_ref(), digest)
+            .map_err(|_| HandlerError::DigestMismatch)?;
+
+        store.commit_partial(upload_id, attachment_id)?;
+        Ok(())
+    })?;
+
+    Ok(UploadedAttachment {
+        attachment_id,
+        expires: attachment.expires,
+        limits: limits.clone()
+    })
+}
+
+// Discards uploads which have made no progress within the upload timeout
+pub fn expire_uploads(storage: &dyn Storage, store: &dyn BlobStore, limits: &AttachmentLimits) -> Result<usize, HandlerError> {
+    let cutoff = Utc::now() - Duration::seconds(limits.upload_timeout);
+    let expired = storage.expire_uploads(cutoff)?;
+
+    for upload_id in &expired {
+        store.delete_partial(*upload_id)?;
+    }
+    Ok(expired.len())
+}
@@ -0,0 +1,94 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+use crate::utils::InternalError;
+
+// Storage for client-side encrypted blobs. Implementations for S3-compatible
+// object stores only need to map these operations onto object keys.
+pub trait BlobStore: Send + Sync {
+    fn put(&self, id: Uuid, data: &[u8]) -> Result<(), InternalError>;
+    // Ok(None) if no blob is stored under the id
+    fn get(&self, id: Uuid) -> Result<Option<Vec<u8>>, InternalError>;
+    // Deleting a missing blob is not an error
+    fn delete(&self, id: Uuid) -> Result<(), InternalError>;
+
+    // Partial blobs are assembled from chunks during resumable uploads, and are
+    // invisible to `get` until committed.
+    fn write_partial(&self, upload_id: Uuid, offset: u64, data: &[u8]) -> Result<(), InternalError>;
+    fn read_partial(&self, upload_id: Uuid) -> Result<Option<Vec<u8>>, InternalError>;
+    // Makes a complete partial blob available under `id`
+    fn commit_partial(&self, upload_id: Uuid, id: Uuid) -> Result<(), InternalError>;
+    fn delete_partial(&self, upload_id: Uuid) -> Result<(), InternalError>;
+}
+
+// Stores each blob as a file named by its id
+pub struct LocalBlobStore {
+    root: PathBuf
+}
+
+impl LocalBlobStore {
+    pub fn new(root: PathBuf) -> std::io::Result<Self> {
+        fs::create_dir_all(root.join("partial"))?;
+        Ok(LocalBlobStore { root })
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.root.join(id.to_simple().to_string())
+    }
+
+    fn partial_path(&self, upload_id: Uuid) -> PathBuf {
+        self.root.join("partial").join(upload_id.to_simple().to_string())
+    }
+}
+
+fn read_optional(path: PathBuf) -> Result<Option<Vec<u8>>, InternalError> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(InternalError::StorageError(e))
+    }
+}
+
+fn remove_optional(path: PathBuf) -> Result<(), InternalError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(InternalError::StorageError(e)),
+        _ => Ok(())
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put(&self, id: Uuid, data: &[u8]) -> Result<(), InternalError> {
+        fs::write(self.path(id), data).map_err(InternalError::StorageError)
+    }
+
+    fn get(&self, id: Uuid) -> Result<Option<Vec<u8>>, InternalError> {
+        read_optional(self.path(id))
+    }
+
+    fn delete(&self, id: Uuid) -> Result<(), InternalError> {
+        remove_optional(self.path(id))
+    }
+
+    fn write_partial(&self, upload_id: Uuid, offset: u64, data: &[u8]) -> Result<(), InternalError> {
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false)
+            .open(self.partial_path(upload_id))
+            .map_err(InternalError::StorageError)?;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(data))
+            .map_err(InternalError::StorageError)
+    }
+
+    fn read_partial(&self, upload_id: Uuid) -> Result<Option<Vec<u8>>, InternalError> {
+        read_optional(self.partial_path(upload_id))
+    }
+
+    fn commit_partial(&self, upload_id: Uuid, id: Uuid) -> Result<(), InternalError> {
+        fs::rename(self.partial_path(upload_id), self.path(id)).map_err(InternalError::StorageError)
+    }
+
+    fn delete_partial(&self, upload_id: Uuid) -> Result<(), InternalError> {
+        remove_optional(self.partial_path(upload_id))
+    }
+}
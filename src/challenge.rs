@@ -0,0 +1,101 @@
+use actix_web::HttpRequest;
+use chrono::Utc;
+use ring::{digest, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryInto;
+use beacon_protocol::v1::session::{Challenge, CHALLENGE_HEADER, CHALLENGE_SOLUTION_HEADER};
+use crate::utils::{HandlerError, InternalError};
+
+// A challenge token is: random (16) | expiry, seconds since the epoch (8, big endian) | difficulty (1) | HMAC tag (32)
+const RANDOM_LEN: usize = 16;
+const SIGNED_LEN: usize = RANDOM_LEN + 8 + 1;
+const TOKEN_LEN: usize = SIGNED_LEN + 32;
+
+// Stateless proof-of-work puzzles gating anonymous registration. The server only
+// needs its key to verify a solution. Solutions are bound to something the request
+// can only use once, so one solved challenge can't register repeatedly before it expires.
+pub struct ChallengeConfig {
+    // Leading zero bits required of SHA-256(token | binding | solution). Zero disables the check.
+    difficulty: u8,
+    // In seconds
+    ttl: i64,
+    key: hmac::Key,
+}
+
+impl ChallengeConfig {
+    pub fn new(difficulty: u8, ttl: i64, key: hmac::Key) -> Self {
+        ChallengeConfig { difficulty, ttl, key }
+    }
+
+    pub fn from_env(rng: &SystemRandom) -> Self {
+        let difficulty = dotenv::var("POW_DIFFICULTY")
+            .map(|s| s.parse().expect("POW_DIFFICULTY must be a number of bits"))
+            .unwrap_or(0);
+        let ttl = dotenv::var("POW_TTL")
+            .map(|s| s.parse().expect("POW_TTL must be a number"))
+            .unwrap_or(5 * 60);
+        // Instances behind a load balancer must share a secret to accept each other's challenges
+        let key = match dotenv::var("POW_SECRET") {
+            Ok(secret) => hmac::Key::new(hmac::HMAC_SHA256,
+                                         &base64::decode(&secret).expect("POW_SECRET must be base64")),
+            Err(_) => hmac::Key::generate(hmac::HMAC_SHA256, rng).expect("Failed to generate challenge key"),
+        };
+        ChallengeConfig::new(difficulty, ttl, key)
+    }
+
+    pub fn issue(&self, rng: &SystemRandom) -> Result<Challenge, HandlerError> {
+        let mut token = vec![0u8; RANDOM_LEN];
+        rng.fill(&mut token).map_err(|_e| InternalError::RNGError)?;
+        let expires = Utc::now().timestamp() + self.ttl;
+        token.extend_from_slice(&expires.to_be_bytes());
+        token.push(self.difficulty);
+        let tag = hmac::sign(&self.key, &token);
+        token.extend_from_slice(tag.as_ref());
+
+        Ok(Challenge { challenge: token, difficulty: self.difficulty, expires })
+    }
+
+    // Checks the X-CHALLENGE and X-CHALLENGE-SOLUTION headers, if challenges are enabled.
+    // `binding` is what the solution was computed for, e.g. the nonce a device registers with.
+    pub fn verify(&self, req: &HttpRequest, binding: &[u8]) -> Result<(), HandlerError> {
+        if self.difficulty == 0 {
+            return Ok(());
+        }
+        let get_header = |n: &str| req.headers().get(n)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| base64::decode(header).ok());
+        let (token, solution) = match (get_header(CHALLENGE_HEADER), get_header(CHALLENGE_SOLUTION_HEADER)) {
+            (Some(token), Some(solution)) => (token, solution),
+            _ => return Err(HandlerError::ChallengeRequired)
+        };
+        if token.len() != TOKEN_LEN {
+            return Err(HandlerError::ChallengeFailed);
+        }
+
+        let (signed, tag) = token.split_at(SIGNED_LEN);
+        hmac::verify(&self.key, signed, tag).map_err(|_e| HandlerError::ChallengeFailed)?;
+        let expires = i64::from_be_bytes(signed[RANDOM_LEN..RANDOM_LEN + 8].try_into().expect("Slice is 8 bytes"));
+        let difficulty = signed[RANDOM_LEN + 8];
+        if expires < Utc::now().timestamp() || difficulty < self.difficulty {
+            return Err(HandlerError::ChallengeFailed);
+        }
+
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&token);
+        ctx.update(binding);
+        ctx.update(&solution);
+        match leading_zero_bits(ctx.finish().as_ref()) >= difficulty as u32 {
+            true => Ok(()),
+            false => Err(HandlerError::ChallengeFailed)
+        }
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 { break; }
+    }
+    bits
+}
@@ -0,0 +1,10 @@
+pub use beacon_protocol::crypto::KeyAlgorithm;
+use crate::utils::HandlerError;
+
+// Rejects keys which cannot possibly be valid for the algorithm
+pub fn check_public_key(algorithm: KeyAlgorithm, public_key: &[u8]) -> Result<(), HandlerError> {
+    match algorithm.is_valid_public_key(public_key) {
+        true => Ok(()),
+        false => Err(HandlerError::MalformedKey { algorithm })
+    }
+}
@@ -1,6 +1,5 @@
 use diesel::pg::PgConnection;
 use diesel::r2d2::ConnectionManager;
-use dotenv;
 use crate::utils::{HandlerError, InternalError};
 use r2d2::PooledConnection;
 
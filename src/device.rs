@@ -1,13 +1,30 @@
-use crate::database::{Pool, extract_connection};
 use uuid::Uuid;
-use crate::utils::{HandlerError, InternalError};
-use crate::schema::devices;
-use diesel::prelude::*;
-
-pub fn create_device(pool: &Pool, public_key: &Vec<u8>) -> Result<Uuid, HandlerError> {
-    let conn = extract_connection(pool)?;
-    diesel::insert_into(devices::table)
-        .values(devices::public_key.eq(public_key))
-        .returning(devices::id)
-        .get_result::<Uuid>(&conn).map_err(|e| InternalError::DatabaseError(e).into())
-}
\ No newline at end of file
+use crate::utils::HandlerError;
+use crate::session;
+use crate::storage::Storage;
+use crate::crypto::{self, KeyAlgorithm};
+use ring::rand::SystemRandom;
+use crate::push::PushProviders;
+use beacon_protocol::v1::session::PushRegistration;
+
+// Registers a device, which must prove possession of its key by signing the nonce of a
+// fresh session. Returns the device id and the rotated session nonce.
+pub fn create_device(store: &dyn Storage, rng: &SystemRandom, public_key: &[u8], algorithm: KeyAlgorithm, nonce: &[u8], signed_nonce: &[u8]) -> Result<(Uuid, Vec<u8>), HandlerError> {
+    crypto::check_public_key(algorithm, public_key)?;
+    algorithm.verify(public_key, nonce, signed_nonce)
+        .map_err(|_e| HandlerError::SignatureMismatch)?;
+
+    let rotation = session::nonce_rotation(nonce.to_vec(), rng)?;
+    let device_id = store.create_device(public_key, algorithm, &rotation)?;
+    Ok((device_id, rotation.new_nonce))
+}
+
+// Replaces any token the device had, so only the app's current registration is woken
+pub fn set_push_token(store: &dyn Storage, providers: &PushProviders, device_id: Uuid, push: &PushRegistration) -> Result<(), HandlerError> {
+    providers.validate(&push.provider, &push.token)?;
+    store.set_push_token(device_id, Some(push))
+}
+
+pub fn clear_push_token(store: &dyn Storage, device_id: Uuid) -> Result<(), HandlerError> {
+    store.set_push_token(device_id, None)
+}
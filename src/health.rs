@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::Serialize;
+use crate::storage::{Storage, SchemaVersion};
+use crate::utils::{HandlerError, InternalError};
+
+// Cleared once the server starts shutting down, so orchestrators stop routing to it
+pub struct Readiness(AtomicBool);
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness::new()
+    }
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Readiness(AtomicBool::new(true))
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn shutting_down(&self) {
+        self.0.store(false, Ordering::SeqCst)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum ReadinessReport {
+    Ready,
+    ShuttingDown,
+    DatabaseUnavailable,
+    SchemaMismatch { expected: &'static str, found: Option<String> },
+}
+
+// Checks the database can be reached and the schema is at the expected version
+pub fn check_database(store: &dyn Storage) -> Result<ReadinessReport, HandlerError> {
+    let SchemaVersion { expected, found } = match store.schema_version() {
+        Ok(version) => version,
+        Err(HandlerError::InternalError { error: InternalError::PoolError(_) }) => return Ok(ReadinessReport::DatabaseUnavailable),
+        Err(e) => return Err(e)
+    };
+
+    Ok(match found.as_deref() == Some(expected) {
+        true => ReadinessReport::Ready,
+        false => ReadinessReport::SchemaMismatch { expected, found }
+    })
+}
@@ -0,0 +1,39 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{select, Either, FutureExt};
+
+// A task run periodically until stopped. Dropping the handle also stops it.
+pub struct Job {
+    name: &'static str,
+    stop: oneshot::Sender<()>,
+    done: oneshot::Receiver<()>,
+}
+
+impl Job {
+    // Runs `run` every `period`, starting immediately
+    pub fn spawn<F, Fut>(name: &'static str, period: Duration, mut run: F) -> Job
+        where
+            F: FnMut() -> Fut + 'static,
+            Fut: Future<Output = ()>,
+    {
+        let (stop, mut stopped) = oneshot::channel();
+        let (finished, done) = oneshot::channel();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(period);
+            while let Either::Left(_) = select(interval.tick().boxed_local(), &mut stopped).await {
+                run().await;
+            }
+            let _ = finished.send(());
+        });
+        Job { name, stop, done }
+    }
+
+    // Lets a run in progress finish, then prevents any more
+    pub async fn stop(self) {
+        info!("Stopping {}", self.name);
+        let _ = self.stop.send(());
+        let _ = self.done.await;
+    }
+}
@@ -0,0 +1,447 @@
+// Diesel 1.4's derives put their impls inside constants, which newer compilers warn about
+#![allow(non_local_definitions)]
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate log;
+
+use actix_web::{web, guard, App, HttpResponse, Responder, HttpRequest, Route};
+use crate::storage::{Storage, PgStorage, SqliteStorage, MemoryStorage};
+use uuid::Uuid;
+use crate::utils::{HandlerError, InternalError, block};
+use actix_web::error::BlockingError;
+use crate::session::SessionInfo;
+use ring::rand::SystemRandom;
+use crate::wire::{Format, Wire};
+use crate::message_types::MessageTypeRegistry;
+use std::env;
+use actix_web::http::{header, HeaderName, HeaderValue, Method};
+use crate::blobstore::{BlobStore, LocalBlobStore};
+use std::sync::Arc;
+use crate::ratelimit::{RateLimit, BucketConfig, KeyBy};
+use crate::challenge::ChallengeConfig;
+use crate::health::{Readiness, ReadinessReport};
+use actix_web::dev::{Body, RequestHead, ServiceRequest, ServiceResponse};
+use actix_service::ServiceFactory;
+use crate::versioning::Deprecated;
+use crate::openapi::Handler;
+use crate::jobs::Job;
+use crate::push::PushProviders;
+use beacon_protocol::base64enc;
+use beacon_protocol::v1;
+use beacon_protocol::v1::session::{RegisterDeviceRequest, RegisterDeviceResponse, PushRegistration, NEW_NONCE_HEADER};
+use beacon_protocol::v1::user::{UserCreation, CreateUserResponse, PreKeyUpdate, OTKAdd, UserSettings, IdentityReset};
+use beacon_protocol::v1::message::{SendMessageRequest, CheckMessagesResponse, MarkRead, RECIPIENT_HEADER, MESSAGE_TYPE_HEADER};
+use beacon_protocol::v1::attachment::{AttachmentLimits, NewUpload, ChunkOffset, FinalizeUpload};
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+pub mod utils;
+mod schema;
+mod database;
+mod session;
+mod user;
+mod device;
+mod message;
+pub mod message_types;
+mod wire;
+pub mod blobstore;
+mod attachment;
+pub mod ratelimit;
+pub mod challenge;
+mod crypto;
+mod metrics;
+pub mod openapi;
+pub mod logging;
+pub mod health;
+pub mod jobs;
+mod migrate;
+pub mod storage;
+pub mod versioning;
+pub mod tls;
+pub mod push;
+
+async fn index() -> impl Responder {
+    HttpResponse::Ok().body("Hello world!")
+}
+
+async fn metrics_endpoint(storage: web::Data<Arc<dyn Storage>>) -> Result<HttpResponse, HandlerError> {
+    block(move || metrics::sample(storage.get_ref().as_ref())).await?;
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics::render()))
+}
+
+// Liveness: answering at all is enough
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "alive" }))
+}
+
+// Readiness: whether this instance should be sent traffic
+async fn readyz(storage: web::Data<Arc<dyn Storage>>, readiness: web::Data<Readiness>) -> Result<HttpResponse, HandlerError> {
+    let report = match readiness.is_ready() {
+        true => block(move || health::check_database(storage.get_ref().as_ref())).await?,
+        false => ReadinessReport::ShuttingDown
+    };
+    Ok(match report {
+        ReadinessReport::Ready => HttpResponse::Ok().json(report),
+        _ => HttpResponse::ServiceUnavailable().json(report)
+    })
+}
+
+async fn api_openapi() -> impl Responder {
+    HttpResponse::Ok().content_type(wire::JSON).body(openapi::rendered())
+}
+
+async fn api_create_session(storage: web::Data<Arc<dyn Storage>>, rng: web::Data<SystemRandom>) -> Result<HttpResponse, HandlerError> {
+    let nonce = block(move || session::new_session_request(storage.get_ref().as_ref(), &rng)).await?;
+    Ok(HttpResponse::Ok().set_header(NEW_NONCE_HEADER, base64::encode(nonce)).finish())
+}
+
+async fn api_new_challenge(challenges: web::Data<ChallengeConfig>, rng: web::Data<SystemRandom>, format: Format) -> Result<HttpResponse, HandlerError> {
+    format.ok(&challenges.issue(&rng)?)
+}
+
+async fn api_create_user(req: HttpRequest, data: Wire<UserCreation>, storage: web::Data<Arc<dyn Storage>>, challenges: web::Data<ChallengeConfig>, session: SessionInfo, format: Format) -> Result<HttpResponse, HandlerError> {
+    let data = data.into_inner();
+    // Emails are unique, so a solution works for one user
+    challenges.verify(&req, data.email.as_bytes())?;
+    let res = block(move || user::create_user(storage.get_ref().as_ref(), data, session.device_id)).await?;
+    format.ok(&CreateUserResponse { user_id: res })
+}
+
+async fn api_register_device(req: HttpRequest, data: Wire<RegisterDeviceRequest>, storage: web::Data<Arc<dyn Storage>>, rng: web::Data<SystemRandom>, challenges: web::Data<ChallengeConfig>, format: Format) -> Result<HttpResponse, HandlerError> {
+    let RegisterDeviceRequest { public_key, algorithm, nonce, signed_nonce } = data.into_inner();
+    // Registering replaces the session's nonce, so a solution works for one device
+    challenges.verify(&req, &nonce)?;
+    let (device_id, new_nonce) = web::block(move || device::create_device(storage.get_ref().as_ref(), &rng, &public_key, algorithm, &nonce, &signed_nonce)).await.map_err(|e| match e {
+        BlockingError::Error(he) => he,
+        BlockingError::Canceled => InternalError::AsyncError.into()
+    })?;
+    let mut res = format.ok(&RegisterDeviceResponse { device_id })?;
+    res.headers_mut().insert(HeaderName::from_static(NEW_NONCE_HEADER),
+                             HeaderValue::from_str(&base64::encode(&new_nonce)).expect("NONCE BASE64 INVALID"));
+    Ok(res)
+}
+
+async fn api_set_push_token(data: Wire<PushRegistration>, storage: web::Data<Arc<dyn Storage>>, push: web::Data<PushProviders>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let registration = data.into_inner();
+    block(move || device::set_push_token(storage.get_ref().as_ref(), &push, session.device_id, &registration)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn api_clear_push_token(storage: web::Data<Arc<dyn Storage>>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    block(move || device::clear_push_token(storage.get_ref().as_ref(), session.device_id)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn api_new_signed_key(data: Wire<PreKeyUpdate>, storage: web::Data<Arc<dyn Storage>>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    block(move || user::update_prekey(storage.get_ref().as_ref(), data.into_inner(), session.user_id.ok_or(HandlerError::AuthenticationError)?)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn api_new_otks(data: Wire<OTKAdd>, storage: web::Data<Arc<dyn Storage>>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let data = data.into_inner().keys;
+    block(move || user::add_otks(storage.get_ref().as_ref(), &data, user_id)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn api_get_chat_package(user_id: web::Path<Uuid>, storage: web::Data<Arc<dyn Storage>>, format: Format) -> Result<HttpResponse, HandlerError> {
+    let response = block(move || user::retrieve_package(storage.get_ref().as_ref(), user_id.into_inner())).await?;
+    format.ok(&response)
+}
+
+async fn api_new_message(data: Wire<SendMessageRequest>, storage: web::Data<Arc<dyn Storage>>, registry: web::Data<MessageTypeRegistry>, push: web::Data<PushProviders>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let mut data = message::NewMessage::json(data.into_inner());
+    data.sender = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let store = storage.clone();
+    let (_, targets) = block(move || message::add_message(store.get_ref().as_ref(), &registry, data)).await?;
+    push.wake(storage.get_ref().clone(), targets);
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Raw ciphertext messages, with the envelope carried in headers
+async fn api_new_binary_message(req: HttpRequest, body: web::Bytes, storage: web::Data<Arc<dyn Storage>>, registry: web::Data<MessageTypeRegistry>, push: web::Data<PushProviders>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let head_err = |n: &str| HandlerError::MalformedHeader { name: n.to_string() };
+    let get_header = |n: &str| { req.headers().get(n)
+        .ok_or(head_err(n))
+        .and_then(|header| header.to_str().map_err(|_e| head_err(n)))};
+
+    let recipient = Uuid::parse_str(get_header(RECIPIENT_HEADER)?)
+        .map_err(|_e| head_err(RECIPIENT_HEADER))?;
+    let message_type = get_header(MESSAGE_TYPE_HEADER)?.to_string();
+
+    let mut data = message::NewMessage::binary(recipient, message_type, body.to_vec());
+    data.sender = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let store = storage.clone();
+    let (_, targets) = block(move || message::add_message(store.get_ref().as_ref(), &registry, data)).await?;
+    push.wake(storage.get_ref().clone(), targets);
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn api_mark_read(data: Wire<MarkRead>, storage: web::Data<Arc<dyn Storage>>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let message_ids = data.into_inner().message_ids;
+    block(move || message::mark_read(storage.get_ref().as_ref(), &message_ids, user_id)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn api_update_settings(data: Wire<UserSettings>, storage: web::Data<Arc<dyn Storage>>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    block(move || user::update_settings(storage.get_ref().as_ref(), data.into_inner(), user_id)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Only a device already registered to the user may replace its identity key
+async fn api_reset_identity(data: Wire<IdentityReset>, storage: web::Data<Arc<dyn Storage>>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    block(move || user::reset_identity(storage.get_ref().as_ref(), data.into_inner(), user_id)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn api_check_messages(req: HttpRequest, storage: web::Data<Arc<dyn Storage>>, session: SessionInfo, format: Format) -> Result<HttpResponse, HandlerError> {
+    let messages = block(move || message::check_mailbox(storage.get_ref().as_ref(), session.device_id)).await?;
+    let wants_binary = req.headers().get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(OCTET_STREAM));
+    if wants_binary {
+        Ok(HttpResponse::Ok().content_type(OCTET_STREAM).body(message::encode_mailbox(&messages)))
+    } else {
+        format.ok(&CheckMessagesResponse{ messages })
+    }
+}
+
+async fn api_upload_attachment(body: web::Bytes, storage: web::Data<Arc<dyn Storage>>, store: web::Data<Arc<dyn BlobStore>>, limits: web::Data<AttachmentLimits>, session: SessionInfo, format: Format) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let uploaded = block(move || attachment::upload_attachment(storage.get_ref().as_ref(), store.get_ref().as_ref(), &limits, user_id, &body)).await?;
+    format.ok(&uploaded)
+}
+
+async fn api_download_attachment(attachment_id: web::Path<Uuid>, storage: web::Data<Arc<dyn Storage>>, store: web::Data<Arc<dyn BlobStore>>, session: SessionInfo) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let data = block(move || attachment::download_attachment(storage.get_ref().as_ref(), store.get_ref().as_ref(), attachment_id.into_inner(), user_id)).await?;
+    Ok(HttpResponse::Ok().content_type(OCTET_STREAM).body(data))
+}
+
+async fn api_attachment_limits(limits: web::Data<AttachmentLimits>, format: Format) -> Result<HttpResponse, HandlerError> {
+    format.ok(limits.get_ref())
+}
+
+async fn api_create_upload(data: Wire<NewUpload>, storage: web::Data<Arc<dyn Storage>>, limits: web::Data<AttachmentLimits>, session: SessionInfo, format: Format) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let size = data.into_inner().size;
+    let progress = block(move || attachment::create_upload(storage.get_ref().as_ref(), &limits, user_id, size)).await?;
+    format.ok(&progress)
+}
+
+async fn api_upload_chunk(upload_id: web::Path<Uuid>, offset: web::Query<ChunkOffset>, body: web::Bytes, storage: web::Data<Arc<dyn Storage>>, store: web::Data<Arc<dyn BlobStore>>, session: SessionInfo, format: Format) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let progress = block(move || attachment::upload_chunk(storage.get_ref().as_ref(), store.get_ref().as_ref(), upload_id.into_inner(), user_id, offset.offset, &body)).await?;
+    format.ok(&progress)
+}
+
+async fn api_upload_progress(upload_id: web::Path<Uuid>, storage: web::Data<Arc<dyn Storage>>, session: SessionInfo, format: Format) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let progress = block(move || attachment::upload_progress(storage.get_ref().as_ref(), upload_id.into_inner(), user_id)).await?;
+    format.ok(&progress)
+}
+
+async fn api_finalize_upload(upload_id: web::Path<Uuid>, data: Wire<FinalizeUpload>, storage: web::Data<Arc<dyn Storage>>, store: web::Data<Arc<dyn BlobStore>>, limits: web::Data<AttachmentLimits>, session: SessionInfo, format: Format) -> Result<HttpResponse, HandlerError> {
+    let user_id = session.user_id.ok_or(HandlerError::AuthenticationError)?;
+    let digest = data.into_inner().digest;
+    let uploaded = block(move || attachment::finalize_upload(storage.get_ref().as_ref(), store.get_ref().as_ref(), &limits, upload_id.into_inner(), user_id, &digest)).await?;
+    format.ok(&uploaded)
+}
+
+// Hourly removal of expired attachments and abandoned uploads
+pub fn spawn_attachment_cleanup(storage: Arc<dyn Storage>, store: Arc<dyn BlobStore>, limits: AttachmentLimits) -> Job {
+    Job::spawn("attachment cleanup", std::time::Duration::from_secs(60 * 60), move || {
+        let (storage, store, limits) = (storage.clone(), store.clone(), limits.clone());
+        async move {
+            let res = block(move || Ok((
+                attachment::expire_attachments(storage.as_ref(), store.as_ref())?,
+                attachment::expire_uploads(storage.as_ref(), store.as_ref(), &limits)?
+            ))).await;
+            match res {
+                Ok((attachments, uploads)) => info!("Expired {} attachments and {} uploads", attachments, uploads),
+                Err(e) => error!("Failed to expire attachments: {:?}", e),
+            }
+        }
+    })
+}
+
+// STORAGE selects where data is kept: "postgres" (the default) at DATABASE_URL,
+// "sqlite" in the file at DATABASE_URL, or "memory", which loses everything on
+// exit and is only meant for development
+pub fn open_storage() -> Arc<dyn Storage> {
+    let storage: Result<Arc<dyn Storage>, String> = match dotenv::var("STORAGE").as_ref().map(String::as_str) {
+        Ok("postgres") | Err(_) => PgStorage::open().map(|s| Arc::new(s) as _),
+        Ok("sqlite") => SqliteStorage::open(&dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set"))
+            .map(|s| Arc::new(s) as _),
+        Ok("memory") => {
+            warn!("Using in-memory storage, nothing will persist");
+            Ok(Arc::new(MemoryStorage::new()))
+        },
+        Ok(other) => panic!("STORAGE must be postgres, sqlite or memory, not {}", other),
+    };
+    storage.unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+// Everything the App instances of each worker share
+#[derive(Clone)]
+pub struct AppState {
+    pub storage: Arc<dyn Storage>,
+    pub rng: SystemRandom,
+    pub store: Arc<dyn BlobStore>,
+    pub limits: AttachmentLimits,
+    pub registry: web::Data<MessageTypeRegistry>,
+    pub push: web::Data<PushProviders>,
+    pub challenges: web::Data<ChallengeConfig>,
+    pub readiness: web::Data<Readiness>,
+    pub anonymous_limit: RateLimit,
+    pub session_limit: RateLimit,
+    pub legacy_api: Deprecated,
+    // Port for admin routes such as /metrics, which may require client certificates.
+    // Without it they aren't served, so they are never exposed on the public port.
+    pub admin_port: Option<u16>,
+}
+
+impl AppState {
+    pub fn from_env(storage: Arc<dyn Storage>) -> std::io::Result<Self> {
+        let rng = ring::rand::SystemRandom::new();
+        let store: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(
+            env::var("ATTACHMENT_DIR").unwrap_or_else(|_| "attachments".to_string()).into())?);
+        let challenges = web::Data::new(ChallengeConfig::from_env(&rng));
+        Ok(AppState {
+            storage,
+            rng,
+            store,
+            limits: attachment::limits_from_env(),
+            registry: web::Data::new(MessageTypeRegistry::default()),
+            push: web::Data::new(PushProviders::from_env()),
+            challenges,
+            readiness: web::Data::new(Readiness::new()),
+            anonymous_limit: RateLimit::new(BucketConfig::from_env("RATE_LIMIT_ANONYMOUS", "10/60"), KeyBy::ip_from_env()),
+            session_limit: RateLimit::new(BucketConfig::from_env("RATE_LIMIT_AUTHENTICATED", "120/60"), KeyBy::Session),
+            legacy_api: Deprecated::from_env("LEGACY_API_SUNSET", v1::PREFIX),
+            admin_port: env::var("ADMIN_PORT").ok().map(|port| port.parse().expect("ADMIN_PORT must be a number")),
+        })
+    }
+}
+
+// The whole API, for HttpServer::new or for embedding in another service
+pub fn build_app(state: AppState) -> App<impl ServiceFactory<Config = (), Request = ServiceRequest, Response = ServiceResponse<Body>, Error = actix_web::Error, InitError = ()>, Body> {
+    let AppState { storage, rng, store, limits, registry, push, challenges, readiness, anonymous_limit, session_limit, legacy_api, admin_port: _ } = state;
+    App::new()
+        .data(storage)
+        .data(rng)
+        .data(store)
+        .data(limits.clone())
+        .app_data(registry)
+        .app_data(push)
+        .app_data(challenges)
+        .app_data(readiness)
+        .wrap(metrics::Metrics)
+        .wrap(logging::RequestTracing)
+        .wrap(wire::NegotiateErrors)
+        .route("/", web::get().to(index))
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+        .service(web::scope(v1::PREFIX)
+            .configure(|cfg| api(cfg, &anonymous_limit, &session_limit, &limits)))
+        // The unversioned paths clients used before /v1, served until the sunset date
+        .service(web::scope("")
+            .wrap(legacy_api)
+            .configure(|cfg| api(cfg, &anonymous_limit, &session_limit, &limits)))
+}
+
+// Routes for operators rather than clients, served only on ADMIN_PORT.
+// Health checks stay on the main port, where orchestrators probe.
+pub fn build_admin_app(state: AppState) -> App<impl ServiceFactory<Config = (), Request = ServiceRequest, Response = ServiceResponse<Body>, Error = actix_web::Error, InitError = ()>, Body> {
+    App::new()
+        .data(state.storage)
+        .wrap(logging::RequestTracing)
+        .route("/metrics", web::get().to(metrics_endpoint))
+}
+
+// Compares only the media type, since clients may add parameters or change its case,
+// which guard::Header's exact match would route to the wrong handler
+fn has_content_type(head: &RequestHead, media_type: &str) -> bool {
+    head.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case(media_type))
+}
+
+// Every API route, relative to the version's prefix. Only documented operations are served,
+// each wrapped in the checks its documentation promises.
+fn api(cfg: &mut web::ServiceConfig, anonymous_limit: &RateLimit, session_limit: &RateLimit, limits: &AttachmentLimits) {
+    let endpoints = openapi::endpoints();
+    let mut paths = Vec::new();
+    for endpoint in &endpoints {
+        if !paths.contains(&endpoint.path) {
+            paths.push(endpoint.path);
+        }
+    }
+    for path in paths {
+        let endpoints = endpoints.iter().filter(|endpoint| endpoint.path == path).collect::<Vec<_>>();
+        let (session, rate_limited) = (endpoints[0].session, endpoints[0].rate_limited);
+        assert!(endpoints.iter().all(|endpoint| endpoint.session == session && endpoint.rate_limited == rate_limited),
+                "Operations on {} must share their session and rate limit checks", path);
+
+        let mut resource = web::resource(path);
+        if endpoints.iter().any(|endpoint| endpoint.attachment) {
+            resource = resource.app_data(web::PayloadConfig::new(limits.max_size));
+        }
+        for endpoint in &endpoints {
+            for route in routes(endpoint.method.clone(), endpoint.handler) {
+                resource = resource.route(route);
+            }
+        }
+        match (session, rate_limited) {
+            (true, true) => cfg.service(resource.wrap(session_limit.clone()).wrap(session::CheckSession)),
+            (true, false) => cfg.service(resource.wrap(session::CheckSession)),
+            (false, true) => cfg.service(resource.wrap(anonymous_limit.clone())),
+            (false, false) => cfg.service(resource),
+        };
+    }
+}
+
+// The handlers serving a documented operation. The match is exhaustive, so every
+// documented operation has one.
+fn routes(method: Method, handler: Handler) -> Vec<Route> {
+    let route = || web::method(method.clone());
+    match handler {
+        Handler::OpenApi => vec![route().to(api_openapi)],
+        Handler::CreateSession => vec![route().to(api_create_session)],
+        Handler::NewChallenge => vec![route().to(api_new_challenge)],
+        Handler::RegisterDevice => vec![route().to(api_register_device)],
+        Handler::SetPushToken => vec![route().to(api_set_push_token)],
+        Handler::ClearPushToken => vec![route().to(api_clear_push_token)],
+        Handler::CreateUser => vec![route().to(api_create_user)],
+        Handler::UpdateSettings => vec![route().to(api_update_settings)],
+        Handler::ResetIdentity => vec![route().to(api_reset_identity)],
+        Handler::ChatPackage => vec![route().to(api_get_chat_package)],
+        Handler::NewSignedKey => vec![route().to(api_new_signed_key)],
+        Handler::NewOnetimeKeys => vec![route().to(api_new_otks)],
+        Handler::SendMessage => vec![
+            route()
+                .guard(guard::fn_guard(|head| has_content_type(head, OCTET_STREAM)))
+                .to(api_new_binary_message),
+            route().to(api_new_message),
+        ],
+        Handler::CheckMessages => vec![route().to(api_check_messages)],
+        Handler::MarkRead => vec![route().to(api_mark_read)],
+        Handler::UploadAttachment => vec![route().to(api_upload_attachment)],
+        Handler::AttachmentLimits => vec![route().to(api_attachment_limits)],
+        Handler::CreateUpload => vec![route().to(api_create_upload)],
+        Handler::UploadChunk => vec![route().to(api_upload_chunk)],
+        Handler::UploadProgress => vec![route().to(api_upload_progress)],
+        Handler::FinalizeUpload => vec![route().to(api_finalize_upload)],
+        Handler::DownloadAttachment => vec![route().to(api_download_attachment)],
+    }
+}
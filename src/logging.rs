@@ -0,0 +1,124 @@
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage};
+use actix_web::http::{HeaderName, HeaderValue};
+use chrono::Utc;
+use futures::future::{ok, Ready};
+use futures::Future;
+use log::{error, info, warn, Level};
+use uuid::Uuid;
+
+use crate::utils::HandlerError;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Configures the global logger. RUST_LOG sets the level (default info) and
+// LOG_FORMAT selects "human" (default) or "json" output, one object per line.
+pub fn init() {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    match dotenv::var("LOG_FORMAT").as_ref().map(String::as_str) {
+        Ok("json") => {
+            builder.format(|buf, record| writeln!(buf, "{}", serde_json::json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })));
+        },
+        Ok("human") | Err(_) => (),
+        Ok(other) => panic!("LOG_FORMAT must be human or json, not {}", other),
+    }
+    builder.init();
+}
+
+// Identifies a request in logs, response headers and error bodies
+#[derive(Clone, Copy)]
+pub struct RequestId(pub Uuid);
+
+impl RequestId {
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.0.to_string()).expect("Uuid is a valid header value")
+    }
+}
+
+fn log_error(request_id: Uuid, error: &Error) {
+    match error.as_error::<HandlerError>() {
+        Some(HandlerError::InternalError { error }) => error!("request_id={} internal error: {:?}", request_id, error),
+        Some(he) => info!("request_id={} rejected: {:?}", request_id, he),
+        None => warn!("request_id={} failed: {}", request_id, error),
+    }
+}
+
+// Assigns each request an id, and logs its outcome
+pub struct RequestTracing;
+
+impl<S: 'static, B> Transform<S> for RequestTracing
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware { service })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let request_id = RequestId(Uuid::new_v4());
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        req.extensions_mut().insert(request_id);
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await;
+            let status = match &mut res {
+                Ok(res) => {
+                    if let Some(e) = res.response().error() {
+                        log_error(request_id.0, e);
+                    }
+                    res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), request_id.header_value());
+                    res.status().as_u16()
+                },
+                Err(e) => {
+                    log_error(request_id.0, e);
+                    e.as_response_error().status_code().as_u16()
+                }
+            };
+            let level = if status >= 500 { Level::Error } else { Level::Info };
+            log::log!(level, "request_id={} method={} path={} status={} duration_ms={}",
+                      request_id.0, method, path, status, start.elapsed().as_millis());
+            res
+        })
+    }
+}
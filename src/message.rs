@@ -1,66 +1,166 @@
 use uuid::Uuid;
-use diesel::prelude::*;
-use serde::{Deserialize, Serialize};
-use crate::database::{Pool, extract_connection};
-use crate::schema::{messages, devices, mailbox};
-use crate::utils::{HandlerError, InternalError};
-use chrono::Utc;
-
-#[derive(Deserialize, Insertable)]
-#[table_name = "messages"]
+use crate::storage::{Storage, Delivered, PushTarget};
+use crate::utils::{HandlerError, Entity};
+use crate::message_types::MessageTypeRegistry;
+pub use beacon_protocol::v1::message::{SendMessageRequest, MailboxReturn, Receipt, ReceiptStatus, RECEIPT_TYPE, SYSTEM_TYPE};
+
 pub struct NewMessage {
-    recipient: Uuid,
-    #[serde(rename="type")]
-    message_type: String,
-    #[serde(skip)]
+    pub recipient: Uuid,
+    pub message_type: String,
     pub sender: Uuid,
 
-    payload: serde_json::Value
+    pub payload: Option<serde_json::Value>,
+    pub ciphertext: Option<Vec<u8>>,
+    // Attachments of the sender's which the recipient may then download
+    pub attachments: Vec<Uuid>,
 }
 
-pub fn add_message(pool: &Pool, msg: NewMessage) -> Result<Uuid, HandlerError> {
-    let conn = extract_connection(pool)?;
+impl NewMessage {
+    pub fn json(msg: SendMessageRequest) -> Self {
+        NewMessage {
+            recipient: msg.recipient,
+            message_type: msg.message_type,
+            sender: Uuid::nil(),
+            payload: msg.payload,
+            ciphertext: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    // A message whose body is an opaque ciphertext rather than JSON
+    pub fn binary(recipient: Uuid, message_type: String, ciphertext: Vec<u8>) -> Self {
+        NewMessage {
+            recipient,
+            message_type,
+            sender: Uuid::nil(),
+            payload: None,
+            ciphertext: Some(ciphertext),
+            attachments: Vec::new(),
+        }
+    }
+
+    // Sent by a user, rather than a receipt or notice generated by the server
+    pub fn is_user_message(&self) -> bool {
+        self.message_type != RECEIPT_TYPE && self.message_type != SYSTEM_TYPE
+    }
 
-    conn.transaction::<Uuid, _, _>( || {
+    // Only messages from users wake devices, so receipts and notices don't count as missed
+    pub fn counts_as_missed(&self) -> bool {
+        self.is_user_message()
+    }
+}
 
-        let device_ids: Vec<Uuid> = devices::table.filter(devices::user_id.eq(msg.recipient))
-            .select(devices::id).load::<Uuid>(&conn)?;
+// Receipts and notices are never acknowledged themselves
+fn wants_receipts(message_type: &str) -> bool {
+    message_type != RECEIPT_TYPE && message_type != SYSTEM_TYPE
+}
 
-        let message_id = diesel::insert_into(messages::table).values(&msg)
-            .returning(messages::id)
-            .get_result::<Uuid>(&conn)?;
+// A receipt for `message_id`, routed back to its original sender
+fn receipt(status: ReceiptStatus, message: &Delivered) -> NewMessage {
+    NewMessage {
+        recipient: message.sender,
+        message_type: RECEIPT_TYPE.to_string(),
+        sender: message.recipient,
+        payload: Some(serde_json::to_value(Receipt { status, message_id: message.message_id })
+            .expect("Receipt serialisation cannot fail")),
+        ciphertext: None,
+        attachments: Vec::new(),
+    }
+}
 
-        let mbox_messages: Vec<_> = device_ids.iter().map(|x| (mailbox::device_id.eq(x), mailbox::message_id.eq(message_id))).collect();
+// Notifies `recipient` of an event concerning `subject`, which is recorded as the sender
+pub fn system_message(recipient: Uuid, subject: Uuid, event: &str) -> NewMessage {
+    NewMessage {
+        recipient,
+        message_type: SYSTEM_TYPE.to_string(),
+        sender: subject,
+        payload: Some(serde_json::json!({ "event": event, "user_id": subject })),
+        ciphertext: None,
+        attachments: Vec::new(),
+    }
+}
 
-        diesel::insert_into(mailbox::table)
-            .values(&mbox_messages)
-            .execute(&conn)?;
-        Ok(message_id)
-    }).map_err(|e| InternalError::DatabaseError(e).into())
+// Ids in a payload's "attachments" field. Types whose payloads may reference attachments
+// declare the field, so validation has already checked it holds only ids.
+fn referenced_attachments(payload: &serde_json::Value) -> Result<Vec<Uuid>, HandlerError> {
+    match payload.get("attachments") {
+        Some(ids) => serde_json::from_value(ids.clone())
+            .map_err(|e| HandlerError::MalformedBody { error_message: e.to_string() }),
+        None => Ok(Vec::new()),
+    }
 }
 
-#[derive(Serialize, Queryable)]
-pub struct MailboxReturn {
-    sender: Uuid,
-    #[serde(rename="type")]
-    message_type: String,
-    timestamp: chrono::DateTime<Utc>,
-    payload: serde_json::Value
+// Returns the message's id, and the recipient's devices to wake for it
+pub fn add_message(store: &dyn Storage, registry: &MessageTypeRegistry, mut msg: NewMessage) -> Result<(Uuid, Vec<PushTarget>), HandlerError> {
+    match (&msg.payload, &msg.ciphertext) {
+        (Some(payload), None) => {
+            registry.validate(&msg.message_type, payload)?;
+            msg.attachments = referenced_attachments(payload)?;
+        },
+        (None, Some(_)) => registry.validate_binary(&msg.message_type)?,
+        _ => return Err(HandlerError::MalformedBody { error_message: "message must have exactly one of payload or ciphertext".to_string() })
+    }
+
+    // Only the sender's own attachments can be shared, and only while they are kept
+    msg.attachments.sort();
+    msg.attachments.dedup();
+    let owned = store.owned_attachments(msg.sender, &msg.attachments)?;
+    if let Some(unknown) = msg.attachments.iter().find(|id| !owned.contains(id)) {
+        return Err(HandlerError::UnknownEntity { entity: Entity::Attachment { uuid: *unknown } });
+    }
+
+    let recipient = msg.recipient;
+    let message_id = store.insert_messages(&[msg])?.remove(0);
+    Ok((message_id, store.push_targets(recipient)?))
 }
 
-pub fn check_mailbox(pool: &Pool, device_id: Uuid) -> Result<Vec<MailboxReturn>, HandlerError> {
-    let conn = extract_connection(pool)?;
+// Binary framing of a mailbox, used when the client accepts application/octet-stream.
+// Each message is encoded as:
+//   16 bytes  message id
+//   16 bytes  sender id
+//    8 bytes  timestamp, milliseconds since the epoch (big endian)
+//    1 byte   body kind: 0 for a JSON payload, 1 for a ciphertext
+//    2 bytes  length of the message type (big endian), followed by the type in UTF-8
+//    4 bytes  length of the body (big endian), followed by the body
+pub fn encode_mailbox(messages: &Vec<MailboxReturn>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for msg in messages {
+        let (kind, body) = match (&msg.payload, &msg.ciphertext) {
+            (_, Some(ciphertext)) => (1u8, ciphertext.clone()),
+            (Some(payload), None) => (0u8, payload.to_string().into_bytes()),
+            (None, None) => (0u8, b"null".to_vec()),
+        };
+        out.extend_from_slice(msg.id.as_bytes());
+        out.extend_from_slice(msg.sender.as_bytes());
+        out.extend_from_slice(&msg.timestamp.timestamp_millis().to_be_bytes());
+        out.push(kind);
+        out.extend_from_slice(&(msg.message_type.len() as u16).to_be_bytes());
+        out.extend_from_slice(msg.message_type.as_bytes());
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&body);
+    }
+    out
+}
 
-    conn.transaction::<Vec<MailboxReturn>, _, _>( || {
+pub fn check_mailbox(store: &dyn Storage, device_id: Uuid) -> Result<Vec<MailboxReturn>, HandlerError> {
+    // The first device to collect a message acknowledges its delivery
+    store.take_mailbox(device_id, &|delivered| delivered.iter()
+        .filter(|message| wants_receipts(&message.message_type))
+        .map(|message| receipt(ReceiptStatus::Delivered, message))
+        .collect())
+}
 
-        let message_ids = diesel::delete(mailbox::table.filter(
-            mailbox::device_id.eq(device_id)))
-            .returning(mailbox::message_id)
-            .load::<Uuid>(&conn)?;
+// Records messages addressed to `user_id` as read, sending a receipt the first time each is
+// read unless the user has disabled them. Returns the number of receipts sent.
+pub fn mark_read(store: &dyn Storage, message_ids: &[Uuid], user_id: Uuid) -> Result<usize, HandlerError> {
+    if message_ids.is_empty() { return Ok(0) };
+    let read_receipts = store.read_receipts(user_id)?;
 
-        messages::table.filter(messages::id.eq_any(&message_ids))
-            .select((messages::sender, messages::message_type, messages::reception_time, messages::payload))
-            .order(messages::reception_time.asc())
-            .load::<MailboxReturn>(&conn)
-    }).map_err(|e| InternalError::DatabaseError(e).into())
-}
\ No newline at end of file
+    store.mark_read(user_id, message_ids, &|read| match read_receipts {
+        true => read.iter()
+            .filter(|message| wants_receipts(&message.message_type))
+            .map(|message| receipt(ReceiptStatus::Read, message))
+            .collect(),
+        false => Vec::new(),
+    })
+}
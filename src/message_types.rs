@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+use crate::base64enc;
+use crate::message::{Receipt, RECEIPT_TYPE, SYSTEM_TYPE};
+use crate::utils::HandlerError;
+
+pub type PayloadValidator = fn(&serde_json::Value) -> Result<(), String>;
+
+struct MessageType {
+    validator: PayloadValidator,
+    // Messages of this type may only be generated by the server
+    server_only: bool,
+    // Messages of this type may carry a raw ciphertext instead of a JSON payload
+    binary: bool,
+}
+
+pub struct MessageTypeRegistry {
+    types: HashMap<String, MessageType>
+}
+
+impl MessageTypeRegistry {
+    pub fn empty() -> Self {
+        MessageTypeRegistry { types: HashMap::new() }
+    }
+
+    pub fn register(mut self, name: &str, validator: PayloadValidator) -> Self {
+        self.types.insert(name.to_string(), MessageType { validator, server_only: false, binary: false });
+        self
+    }
+
+    // Also accept raw ciphertexts for an already registered type
+    pub fn accept_binary(mut self, name: &str) -> Self {
+        self.types.get_mut(name)
+            .expect("Message type must be registered before accepting binary payloads")
+            .binary = true;
+        self
+    }
+
+    pub fn register_server_only(mut self, name: &str, validator: PayloadValidator) -> Self {
+        self.types.insert(name.to_string(), MessageType { validator, server_only: true, binary: false });
+        self
+    }
+
+    fn client_type(&self, message_type: &str) -> Result<&MessageType, HandlerError> {
+        let entry = self.types.get(message_type)
+            .ok_or(HandlerError::UnknownMessageType { name: message_type.to_string() })?;
+        if entry.server_only {
+            return Err(HandlerError::ReservedMessageType { name: message_type.to_string() });
+        }
+        Ok(entry)
+    }
+
+    // Checks a message submitted by a client
+    pub fn validate(&self, message_type: &str, payload: &serde_json::Value) -> Result<(), HandlerError> {
+        let entry = self.client_type(message_type)?;
+        (entry.validator)(payload).map_err(|error_message| HandlerError::InvalidPayload {
+            message_type: message_type.to_string(),
+            error_message
+        })
+    }
+
+    // Checks a message submitted by a client with a raw ciphertext body
+    pub fn validate_binary(&self, message_type: &str) -> Result<(), HandlerError> {
+        match self.client_type(message_type)?.binary {
+            true => Ok(()),
+            false => Err(HandlerError::InvalidPayload {
+                message_type: message_type.to_string(),
+                error_message: "binary payloads are not accepted for this type".to_string()
+            })
+        }
+    }
+}
+
+impl Default for MessageTypeRegistry {
+    fn default() -> Self {
+        MessageTypeRegistry::empty()
+            .register("ciphertext", shape::<Ciphertext>)
+            .register("prekey-ciphertext", shape::<PrekeyCiphertext>)
+            // Prekey ciphertexts also carry keys, which a raw body has no room for
+            .accept_binary("ciphertext")
+            .register("typing", shape::<Typing>)
+            .register_server_only(RECEIPT_TYPE, shape::<Receipt>)
+            .register_server_only(SYSTEM_TYPE, shape::<System>)
+    }
+}
+
+// Accepts any payload which deserializes into T. The shapes below are only deserialized to
+// check them, so their fields are never read.
+fn shape<T: DeserializeOwned>(payload: &serde_json::Value) -> Result<(), String> {
+    T::deserialize(payload).map(|_| ()).map_err(|e| e.to_string())
+}
+
+// Payloads reference attachments by listing their ids in an "attachments" field
+#[derive(Deserialize)]
+#[allow(dead_code)]
+#[serde(deny_unknown_fields)]
+struct Ciphertext {
+    #[serde(with = "base64enc")]
+    body: Vec<u8>,
+    #[serde(default)]
+    attachments: Vec<Uuid>,
+}
+
+// The first message of a conversation, carrying the sender's X3DH parameters
+#[derive(Deserialize)]
+#[allow(dead_code)]
+#[serde(deny_unknown_fields)]
+struct PrekeyCiphertext {
+    #[serde(with = "base64enc")]
+    body: Vec<u8>,
+    #[serde(with = "base64enc")]
+    ephemeral_key: Vec<u8>,
+    #[serde(default, with = "base64enc::option")]
+    onetime_key: Option<Vec<u8>>,
+    #[serde(default)]
+    attachments: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+#[serde(deny_unknown_fields)]
+struct Typing {
+    typing: bool,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct System {
+    event: String,
+    user_id: Option<Uuid>,
+}
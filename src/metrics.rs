@@ -0,0 +1,170 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, http::Method, Error};
+use futures::future::{ok, Ready};
+use futures::Future;
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder};
+use prometheus::{register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge};
+use crate::openapi;
+use crate::storage::Storage;
+use crate::utils::HandlerError;
+use beacon_protocol::v1;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "beacon_http_requests_total", "HTTP requests served", &["route", "method", "status"]).unwrap();
+    static ref HTTP_LATENCY: HistogramVec = register_histogram_vec!(
+        "beacon_http_request_duration_seconds", "HTTP request latency", &["route", "method"]).unwrap();
+    static ref HANDLER_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "beacon_handler_errors_total", "Errors returned to clients", &["type"]).unwrap();
+    static ref POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "beacon_db_pool_connections", "Database connections held by the pool").unwrap();
+    static ref POOL_IDLE: IntGauge = register_int_gauge!(
+        "beacon_db_pool_idle_connections", "Idle database connections in the pool").unwrap();
+    static ref POOL_MAX: IntGauge = register_int_gauge!(
+        "beacon_db_pool_max_connections", "Maximum size of the database pool").unwrap();
+    static ref MAILBOX_BACKLOG: IntGauge = register_int_gauge!(
+        "beacon_mailbox_backlog", "Messages waiting in device mailboxes").unwrap();
+    pub static ref PREKEY_EXHAUSTION: IntCounter = register_int_counter!(
+        "beacon_prekey_exhaustion_total", "Chat packages refused for lack of one-time keys").unwrap();
+    pub static ref SESSIONS_CREATED: IntCounter = register_int_counter!(
+        "beacon_sessions_created_total", "Sessions opened").unwrap();
+    // Every path template served, in the order the router tries them
+    static ref ROUTES: Vec<String> = {
+        let mut routes: Vec<String> = vec!["/", "/healthz", "/readyz"].into_iter().map(String::from).collect();
+        for prefix in &[v1::PREFIX, ""] {
+            routes.extend(openapi::endpoints().into_iter().map(|endpoint| format!("{}{}", prefix, endpoint.path)));
+        }
+        routes
+    };
+    static ref LAST_SAMPLE: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+// Scrapes more often than this reuse the previous sample rather than querying the database
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+// The template the path was routed by, so labels are bounded by the routes rather than the requests
+fn route_label(path: &str) -> &'static str {
+    let segments = path.split('/').collect::<Vec<_>>();
+    ROUTES.iter()
+        .find(|route| {
+            let template = route.split('/').collect::<Vec<_>>();
+            template.len() == segments.len() && template.iter().zip(&segments)
+                .all(|(expected, segment)| expected == segment || (expected.starts_with('{') && !segment.is_empty()))
+        })
+        .map_or("unmatched", String::as_str)
+}
+
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::PATCH => "PATCH",
+        _ => "other",
+    }
+}
+
+fn record_error(error: &Error) {
+    if let Some(he) = error.as_error::<HandlerError>() {
+        HANDLER_ERRORS.with_label_values(&[he.kind()]).inc();
+    }
+}
+
+// Refreshes the gauges which are sampled rather than updated as events happen, at most
+// once per SAMPLE_INTERVAL
+pub fn sample(store: &dyn Storage) -> Result<(), HandlerError> {
+    let mut last = LAST_SAMPLE.lock().expect("Sample lock poisoned");
+    if last.is_some_and(|at| at.elapsed() < SAMPLE_INTERVAL) {
+        return Ok(());
+    }
+    if let Some(stats) = store.connection_stats() {
+        POOL_CONNECTIONS.set(stats.connections as i64);
+        POOL_IDLE.set(stats.idle as i64);
+        POOL_MAX.set(stats.max as i64);
+    }
+    MAILBOX_BACKLOG.set(store.mailbox_backlog()?);
+    *last = Some(Instant::now());
+    Ok(())
+}
+
+// Prometheus text exposition of every registered metric
+pub fn render() -> Vec<u8> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)
+        .expect("Metrics encoding cannot fail");
+    buffer
+}
+
+pub struct Metrics;
+
+impl<S: 'static, B> Transform<S> for Metrics
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MetricsMiddleware { service })
+    }
+}
+
+pub struct MetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for MetricsMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let route = route_label(req.path());
+        let method = method_label(req.method());
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => {
+                    if let Some(e) = res.response().error() {
+                        record_error(e);
+                    }
+                    res.status()
+                },
+                Err(e) => {
+                    record_error(e);
+                    e.as_response_error().status_code()
+                }
+            };
+            HTTP_REQUESTS.with_label_values(&[route, method, status.as_str()]).inc();
+            HTTP_LATENCY.with_label_values(&[route, method]).observe(start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
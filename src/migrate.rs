@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+use diesel::pg::PgConnection;
+use diesel::sqlite::SqliteConnection;
+use diesel_migrations::{setup_database, MigrationConnection, RunMigrationsError};
+
+include!(concat!(env!("OUT_DIR"), "/migration_versions.rs"));
+
+mod postgres {
+    embed_migrations!("migrations");
+    pub use self::embedded_migrations::run;
+}
+
+mod sqlite {
+    embed_migrations!("migrations_sqlite");
+    pub use self::embedded_migrations::run;
+}
+
+// The migrations embedded in this build for one database
+pub struct Migrations<C: 'static> {
+    versions: &'static [&'static str],
+    run: fn(&C) -> Result<(), RunMigrationsError>,
+}
+
+pub const POSTGRES: Migrations<PgConnection> = Migrations { versions: POSTGRES_VERSIONS, run: postgres::run };
+pub const SQLITE: Migrations<SqliteConnection> = Migrations { versions: SQLITE_VERSIONS, run: sqlite::run };
+
+impl<C: MigrationConnection> Migrations<C> {
+    // Version of the newest migration
+    pub fn expected_version(&self) -> &'static str {
+        self.versions.last().expect("At least one migration is embedded")
+    }
+
+    // Brings the schema up to the embedded version, or explains why the server cannot
+    // start. Pending migrations are only applied when RUN_MIGRATIONS allows it (the
+    // default), and a database with migrations this build doesn't know is never touched.
+    pub fn check_schema(&self, conn: &C) -> Result<(), String> {
+        let run_migrations = dotenv::var("RUN_MIGRATIONS")
+            .map(|s| s.parse().expect("RUN_MIGRATIONS must be true or false"))
+            .unwrap_or(true);
+        setup_database(conn).map_err(|e| format!("Failed to create migrations table: {}", e))?;
+        let applied = conn.previously_run_migration_versions()
+            .map_err(|e| format!("Failed to read applied migrations: {}", e))?;
+
+        let known = self.versions.iter().map(|v| v.to_string()).collect::<HashSet<_>>();
+        let mut unknown = applied.difference(&known).collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(format!("Database has migrations this build doesn't know about: {:?}", unknown));
+        }
+
+        let pending = self.versions.iter().filter(|v| !applied.contains(**v)).count();
+        match (pending, run_migrations) {
+            (0, _) => info!("Schema is at version {}", self.expected_version()),
+            (_, true) => {
+                info!("Applying {} pending migrations", pending);
+                (self.run)(conn).map_err(|e| format!("Failed to run migrations: {}", e))?;
+            },
+            (_, false) => return Err(format!("{} migrations are pending and RUN_MIGRATIONS is false", pending)),
+        }
+        Ok(())
+    }
+}
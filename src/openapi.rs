@@ -0,0 +1,372 @@
+use lazy_static::lazy_static;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use actix_web::http::Method;
+use serde_json::{json, Map, Value};
+
+use crate::wire::{JSON, MSGPACK};
+use crate::OCTET_STREAM;
+use beacon_protocol::v1;
+use beacon_protocol::v1::attachment::{AttachmentLimits, UploadedAttachment, NewUpload, UploadProgress, FinalizeUpload};
+use beacon_protocol::v1::error::ErrorBody;
+use beacon_protocol::v1::message::{SendMessageRequest, CheckMessagesResponse, MarkRead, RECIPIENT_HEADER, MESSAGE_TYPE_HEADER};
+use beacon_protocol::v1::session::{Challenge, RegisterDeviceRequest, RegisterDeviceResponse, PushRegistration, DEVICE_ID_HEADER, NONCE_HEADER,
+                                   SIGNED_NONCE_HEADER, NEW_NONCE_HEADER, CHALLENGE_HEADER, CHALLENGE_SOLUTION_HEADER};
+use beacon_protocol::v1::user::{UserCreation, CreateUserResponse, PreKeyUpdate, OTKAdd, ChatPackage, UserSettings, IdentityReset};
+
+lazy_static! {
+    static ref DOCUMENT: String = serde_json::to_string_pretty(&document())
+        .expect("OpenAPI document serialisation cannot fail");
+}
+
+// The document served at /v1/openapi.json, built once
+pub fn rendered() -> &'static str {
+    DOCUMENT.as_str()
+}
+
+// An OpenAPI 3 description of the v1 API. Schemas are derived from the protocol types,
+// and `api` serves exactly the operations listed here.
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let operations = operations(&mut gen);
+    let error = gen.subschema_for::<ErrorBody>();
+
+    let mut paths = Map::new();
+    for operation in operations {
+        let method = operation.method.as_str().to_lowercase();
+        let item = paths.entry(operation.path).or_insert_with(|| json!({}));
+        item[method.as_str()] = operation.render();
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "Beacon", "version": env!("CARGO_PKG_VERSION") },
+        "servers": [{ "url": v1::PREFIX }],
+        "paths": paths,
+        "components": {
+            "schemas": gen.take_definitions(),
+            "parameters": {
+                "DeviceId": header(DEVICE_ID_HEADER, "Device the session belongs to", uuid(), true),
+                "Nonce": header(NONCE_HEADER, "The session's current nonce", base64(), true),
+                "SignedNonce": header(SIGNED_NONCE_HEADER, "The nonce, signed with the device key", base64(), true),
+                "Challenge": header(CHALLENGE_HEADER,
+                    "Token from /challenge/new. Required when the challenge's difficulty is above zero.",
+                    json!({ "type": "string" }), false),
+                "ChallengeSolution": header(CHALLENGE_SOLUTION_HEADER,
+                    "Solution to the challenge. Required when the challenge's difficulty is above zero.",
+                    base64(), false),
+            },
+            "headers": {
+                "NewNonce": { "description": "The nonce to sign for the next request", "schema": base64() },
+            },
+            "responses": {
+                "Error": {
+                    "description": "The request failed. The status code depends on the error type.",
+                    "content": encoded(serde_json::to_value(error).expect("Schemas are JSON")),
+                },
+            },
+        },
+    })
+}
+
+// Which handler serves an operation. `api` matches on these exhaustively, so a
+// documented operation without a handler doesn't compile.
+#[derive(Clone, Copy)]
+pub enum Handler {
+    OpenApi,
+    CreateSession,
+    NewChallenge,
+    RegisterDevice,
+    SetPushToken,
+    ClearPushToken,
+    CreateUser,
+    UpdateSettings,
+    ResetIdentity,
+    ChatPackage,
+    NewSignedKey,
+    NewOnetimeKeys,
+    SendMessage,
+    CheckMessages,
+    MarkRead,
+    UploadAttachment,
+    AttachmentLimits,
+    CreateUpload,
+    UploadChunk,
+    UploadProgress,
+    FinalizeUpload,
+    DownloadAttachment,
+}
+
+// What `api` needs to serve a documented operation
+pub struct Endpoint {
+    pub method: Method,
+    pub path: &'static str,
+    pub handler: Handler,
+    pub session: bool,
+    pub rate_limited: bool,
+    // Takes attachment data, so accepts bodies up to the attachment size limit
+    pub attachment: bool,
+}
+
+// Every documented operation, in the order `api` routes them
+pub fn endpoints() -> Vec<Endpoint> {
+    operations(&mut SchemaSettings::openapi3().into_generator()).into_iter()
+        .map(|operation| Endpoint {
+            method: operation.method,
+            path: operation.path,
+            handler: operation.handler,
+            session: operation.session,
+            rate_limited: operation.rate_limited,
+            attachment: operation.attachment,
+        })
+        .collect()
+}
+
+struct Operation {
+    method: Method,
+    path: &'static str,
+    handler: Handler,
+    summary: &'static str,
+    session: bool,
+    challenge: bool,
+    new_nonce: bool,
+    rate_limited: bool,
+    attachment: bool,
+    parameters: Vec<Value>,
+    request: Map<String, Value>,
+    response: Map<String, Value>,
+}
+
+impl Operation {
+    fn new(method: Method, path: &'static str, handler: Handler, summary: &'static str) -> Self {
+        Operation { method, path, handler, summary, session: false, challenge: false, new_nonce: false,
+            rate_limited: true, attachment: false, parameters: Vec::new(), request: Map::new(), response: Map::new() }
+    }
+
+    fn get(path: &'static str, handler: Handler, summary: &'static str) -> Self {
+        Operation::new(Method::GET, path, handler, summary)
+    }
+
+    fn post(path: &'static str, handler: Handler, summary: &'static str) -> Self {
+        Operation::new(Method::POST, path, handler, summary)
+    }
+
+    fn put(path: &'static str, handler: Handler, summary: &'static str) -> Self {
+        Operation::new(Method::PUT, path, handler, summary)
+    }
+
+    fn delete(path: &'static str, handler: Handler, summary: &'static str) -> Self {
+        Operation::new(Method::DELETE, path, handler, summary)
+    }
+
+    // Requires the session headers, and answers with the next nonce
+    fn session(mut self) -> Self {
+        self.session = true;
+        self.new_nonce = true;
+        self
+    }
+
+    // Requires a solved proof-of-work challenge, when the server has them enabled
+    fn challenge(mut self) -> Self {
+        self.challenge = true;
+        self
+    }
+
+    // Answers with the nonce for a newly opened session
+    fn new_nonce(mut self) -> Self {
+        self.new_nonce = true;
+        self
+    }
+
+    // Served without counting against a rate limit
+    fn unlimited(mut self) -> Self {
+        self.rate_limited = false;
+        self
+    }
+
+    fn header(mut self, name: &str, description: &str, schema: Value, required: bool) -> Self {
+        self.parameters.push(header(name, description, schema, required));
+        self
+    }
+
+    fn query(mut self, name: &str, schema: Value) -> Self {
+        self.parameters.push(json!({ "name": name, "in": "query", "required": true, "schema": schema }));
+        self
+    }
+
+    // JSON or MessagePack, as the Content-Type names
+    fn request<T: JsonSchema>(mut self, gen: &mut SchemaGenerator) -> Self {
+        self.request.extend(encoded(schema::<T>(gen)));
+        self
+    }
+
+    fn request_binary(mut self) -> Self {
+        self.request.insert(OCTET_STREAM.to_string(), json!({ "schema": binary() }));
+        self
+    }
+
+    // Attachment data, which may be larger than other bodies
+    fn request_attachment(mut self) -> Self {
+        self.attachment = true;
+        self.request_binary()
+    }
+
+    // JSON or MessagePack, as the Accept header names
+    fn response<T: JsonSchema>(mut self, gen: &mut SchemaGenerator) -> Self {
+        self.response.extend(encoded(schema::<T>(gen)));
+        self
+    }
+
+    fn response_binary(mut self) -> Self {
+        self.response.insert(OCTET_STREAM.to_string(), json!({ "schema": binary() }));
+        self
+    }
+
+    fn render(self) -> Value {
+        let mut parameters: Vec<Value> = self.path.split('/')
+            .filter(|segment| segment.starts_with('{'))
+            .map(|segment| json!({
+                "name": segment.trim_matches(|c| c == '{' || c == '}'),
+                "in": "path",
+                "required": true,
+                "schema": uuid(),
+            }))
+            .collect();
+        if self.session {
+            parameters.extend(["DeviceId", "Nonce", "SignedNonce"].iter().map(|name| parameter_ref(name)));
+        }
+        if self.challenge {
+            parameters.extend(["Challenge", "ChallengeSolution"].iter().map(|name| parameter_ref(name)));
+        }
+        parameters.extend(self.parameters);
+
+        let mut ok = json!({ "description": "Success" });
+        if self.new_nonce {
+            ok["headers"] = json!({ NEW_NONCE_HEADER: { "$ref": "#/components/headers/NewNonce" } });
+        }
+        if !self.response.is_empty() {
+            ok["content"] = Value::Object(self.response);
+        }
+        let mut operation = json!({
+            "summary": self.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": ok,
+                "default": { "$ref": "#/components/responses/Error" },
+            },
+        });
+        if !self.request.is_empty() {
+            operation["requestBody"] = json!({ "required": true, "content": self.request });
+        }
+        operation
+    }
+}
+
+// Every route under /v1. Paths are matched in this order, so fixed paths must come
+// before templates that would also match them.
+fn operations(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![
+        Operation::get("/openapi.json", Handler::OpenApi, "This document").unlimited(),
+        Operation::post("/session/new", Handler::CreateSession, "Open a session").new_nonce(),
+        Operation::post("/challenge/new", Handler::NewChallenge, "Issue a proof-of-work challenge")
+            .response::<Challenge>(gen),
+        Operation::post("/devices/new", Handler::RegisterDevice, "Register a device with the session")
+            .challenge().new_nonce()
+            .request::<RegisterDeviceRequest>(gen)
+            .response::<RegisterDeviceResponse>(gen),
+        Operation::post("/devices/push", Handler::SetPushToken, "Wake the device through a push provider when messages arrive")
+            .session()
+            .request::<PushRegistration>(gen),
+        Operation::delete("/devices/push", Handler::ClearPushToken, "Stop waking the device")
+            .session(),
+        Operation::post("/users/new", Handler::CreateUser, "Create a user owning the session's device")
+            .session().challenge()
+            .request::<UserCreation>(gen)
+            .response::<CreateUserResponse>(gen),
+        Operation::post("/users/settings", Handler::UpdateSettings, "Update the user's settings")
+            .session()
+            .request::<UserSettings>(gen),
+        Operation::post("/users/identity", Handler::ResetIdentity, "Replace the user's identity key")
+            .session()
+            .request::<IdentityReset>(gen),
+        Operation::post("/users/{user_id}/package", Handler::ChatPackage, "Fetch the keys to start a chat, using up a one-time key")
+            .session()
+            .response::<ChatPackage>(gen),
+        Operation::post("/keys/signed", Handler::NewSignedKey, "Replace the signed prekey")
+            .session()
+            .request::<PreKeyUpdate>(gen),
+        Operation::post("/keys/onetime", Handler::NewOnetimeKeys, "Add one-time prekeys")
+            .session()
+            .request::<OTKAdd>(gen),
+        Operation::post("/messages/send", Handler::SendMessage, "Send a message. Binary bodies carry the envelope in headers.")
+            .session()
+            .request::<SendMessageRequest>(gen)
+            .request_binary()
+            .header(RECIPIENT_HEADER, "Recipient of the message. Required with a binary body.", uuid(), false)
+            .header(MESSAGE_TYPE_HEADER, "Type of the message. Required with a binary body.", json!({ "type": "string" }), false),
+        Operation::post("/messages/mailbox", Handler::CheckMessages, "Take the device's undelivered messages")
+            .session()
+            .response::<CheckMessagesResponse>(gen)
+            .response_binary(),
+        Operation::post("/messages/read", Handler::MarkRead, "Mark messages as read, sending receipts")
+            .session()
+            .request::<MarkRead>(gen),
+        Operation::post("/attachments/new", Handler::UploadAttachment, "Upload an attachment in one request")
+            .session()
+            .request_attachment()
+            .response::<UploadedAttachment>(gen),
+        Operation::post("/attachments/limits", Handler::AttachmentLimits, "Attachment size and retention limits")
+            .session()
+            .response::<AttachmentLimits>(gen),
+        Operation::post("/attachments/uploads/new", Handler::CreateUpload, "Start a resumable upload")
+            .session()
+            .request::<NewUpload>(gen)
+            .response::<UploadProgress>(gen),
+        Operation::put("/attachments/uploads/{upload_id}", Handler::UploadChunk, "Append a chunk to an upload")
+            .session()
+            .query("offset", json!({ "type": "integer", "format": "int64" }))
+            .request_attachment()
+            .response::<UploadProgress>(gen),
+        Operation::post("/attachments/uploads/{upload_id}", Handler::UploadProgress, "Check an upload's progress")
+            .session()
+            .response::<UploadProgress>(gen),
+        Operation::post("/attachments/uploads/{upload_id}/finalize", Handler::FinalizeUpload, "Complete an upload into an attachment")
+            .session()
+            .request::<FinalizeUpload>(gen)
+            .response::<UploadedAttachment>(gen),
+        Operation::post("/attachments/{attachment_id}", Handler::DownloadAttachment, "Download an attachment")
+            .session()
+            .response_binary(),
+    ]
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).expect("Schemas are JSON")
+}
+
+// Bodies may be sent in either format the server speaks
+fn encoded(schema: Value) -> Map<String, Value> {
+    let mut content = Map::new();
+    content.insert(JSON.to_string(), json!({ "schema": schema }));
+    content.insert(MSGPACK.to_string(), json!({ "schema": schema }));
+    content
+}
+
+fn header(name: &str, description: &str, schema: Value, required: bool) -> Value {
+    json!({ "name": name, "in": "header", "required": required, "description": description, "schema": schema })
+}
+
+fn parameter_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/parameters/{}", name) })
+}
+
+fn uuid() -> Value {
+    json!({ "type": "string", "format": "uuid" })
+}
+
+fn base64() -> Value {
+    json!({ "type": "string", "format": "byte" })
+}
+
+fn binary() -> Value {
+    json!({ "type": "string", "format": "binary" })
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_rt::System;
+use actix_web::http::StatusCode;
+use futures::future::{ready, FutureExt, LocalBoxFuture};
+use serde::Serialize;
+
+use crate::storage::{PushTarget, Storage};
+use crate::utils::{block, HandlerError};
+
+// Tokens are opaque to the server, but none issued by a real push service is this long
+const MAX_TOKEN_LENGTH: usize = 4096;
+
+// Wakes a device which has messages waiting. It carries nothing but their number,
+// for the app's badge, so push services never see who sent what; the device
+// collects the messages from its mailbox.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct WakeUp {
+    pub missed_messages: i32,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    // The token is no longer valid, e.g. because the app was uninstalled
+    Unregistered,
+    Failed(String),
+}
+
+// Delivers wake-ups through one push service
+pub trait PushProvider: Send + Sync {
+    fn push(&self, token: &str, wake_up: WakeUp) -> LocalBoxFuture<'static, Result<(), PushError>>;
+}
+
+// POSTs the token and wake-up as JSON to a gateway, which holds the credentials for
+// the platform's push service (APNs, FCM, ...) and translates it into their format.
+// A 404 or 410 means the token is no longer registered.
+pub struct HttpProvider {
+    url: String,
+}
+
+impl HttpProvider {
+    pub fn new(url: &str) -> Self {
+        HttpProvider { url: url.to_string() }
+    }
+}
+
+#[derive(Serialize)]
+struct GatewayRequest<'a> {
+    token: &'a str,
+    #[serde(flatten)]
+    wake_up: WakeUp,
+}
+
+impl PushProvider for HttpProvider {
+    fn push(&self, token: &str, wake_up: WakeUp) -> LocalBoxFuture<'static, Result<(), PushError>> {
+        // awc clients can't be shared between worker threads, so each push makes its own
+        let request = awc::Client::default().post(&self.url)
+            .send_json(&GatewayRequest { token, wake_up });
+        async move {
+            let res = request.await.map_err(|e| PushError::Failed(e.to_string()))?;
+            match res.status() {
+                status if status.is_success() => Ok(()),
+                StatusCode::NOT_FOUND | StatusCode::GONE => Err(PushError::Unregistered),
+                status => Err(PushError::Failed(format!("gateway returned {}", status))),
+            }
+        }.boxed_local()
+    }
+}
+
+// Records wake-ups instead of sending them, for tests and local development
+#[derive(Default)]
+pub struct MockProvider {
+    sent: Mutex<Vec<(String, WakeUp)>>,
+}
+
+impl MockProvider {
+    // Every (token, wake-up) pushed so far
+    pub fn sent(&self) -> Vec<(String, WakeUp)> {
+        self.sent.lock().expect("Mock provider lock poisoned").clone()
+    }
+}
+
+impl PushProvider for MockProvider {
+    fn push(&self, token: &str, wake_up: WakeUp) -> LocalBoxFuture<'static, Result<(), PushError>> {
+        self.sent.lock().expect("Mock provider lock poisoned").push((token.to_string(), wake_up));
+        ready(Ok(())).boxed_local()
+    }
+}
+
+// Counts a wake-up until its task finishes or is dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// The push services devices may register tokens for, by name
+#[derive(Default)]
+pub struct PushProviders {
+    providers: HashMap<String, Arc<dyn PushProvider>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl PushProviders {
+    pub fn empty() -> Self {
+        PushProviders::default()
+    }
+
+    pub fn register(mut self, name: &str, provider: Arc<dyn PushProvider>) -> Self {
+        self.providers.insert(name.to_string(), provider);
+        self
+    }
+
+    // PUSH_PROVIDERS lists gateways as comma separated name=url pairs, e.g.
+    // "apns=https://push.internal/apns,fcm=https://push.internal/fcm"
+    pub fn from_env() -> Self {
+        let value = dotenv::var("PUSH_PROVIDERS").unwrap_or_default();
+        value.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .fold(PushProviders::empty(), |providers, entry| {
+                let mut parts = entry.splitn(2, '=').map(str::trim);
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(url)) if !name.is_empty() && !url.is_empty() =>
+                        providers.register(name, Arc::new(HttpProvider::new(url))),
+                    _ => panic!("PUSH_PROVIDERS must be a comma separated list of name=url pairs"),
+                }
+            })
+    }
+
+    // Checks a token a device wants to register
+    pub fn validate(&self, provider: &str, token: &str) -> Result<(), HandlerError> {
+        if !self.providers.contains_key(provider) {
+            return Err(HandlerError::UnknownPushProvider { name: provider.to_string() });
+        }
+        if token.is_empty() || token.len() > MAX_TOKEN_LENGTH {
+            return Err(HandlerError::MalformedBody { error_message: format!("push tokens must be 1 to {} bytes", MAX_TOKEN_LENGTH) });
+        }
+        Ok(())
+    }
+
+    // Sends wake-ups in the background, so a slow push service never holds up the
+    // sender's request. Tokens a provider no longer recognises are removed.
+    // They run on the system arbiter rather than the worker's, which stops with the
+    // server, so shutdown can wait for them with `drain`.
+    pub fn wake(&self, storage: Arc<dyn Storage>, targets: Vec<PushTarget>) {
+        for PushTarget { device_id, push, missed_messages } in targets {
+            let provider = match self.providers.get(&push.provider) {
+                Some(provider) => provider.clone(),
+                // Left over from a provider which has since been removed from the configuration
+                None => continue,
+            };
+            let storage = storage.clone();
+            let in_flight = InFlight::start(&self.in_flight);
+            System::current().arbiter().exec_fn(move || actix_rt::spawn(async move {
+                let _in_flight = in_flight;
+                match provider.push(&push.token, WakeUp { missed_messages }).await {
+                    Ok(()) => debug!("Woke device {} with {} missed messages", device_id, missed_messages),
+                    Err(PushError::Unregistered) => {
+                        info!("Removing the unregistered push token of device {}", device_id);
+                        if let Err(e) = block(move || storage.clear_push_token_if(device_id, &push.token)).await {
+                            error!("Failed to remove push token: {:?}", e);
+                        }
+                    },
+                    Err(PushError::Failed(e)) => warn!("Failed to wake device {}: {}", device_id, e),
+                }
+            }));
+        }
+    }
+
+    // Waits up to `timeout` for wake-ups still being sent. Any left are dropped when the
+    // system stops.
+    pub async fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let in_flight = self.in_flight.load(Ordering::SeqCst);
+            if in_flight == 0 {
+                return;
+            }
+            if Instant::now() >= deadline {
+                warn!("Abandoning {} wake-ups still being sent", in_flight);
+                return;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(50)).await;
+        }
+    }
+}
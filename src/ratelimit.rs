@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage};
+use futures::future::{ok, ready, Ready};
+use futures::Future;
+
+use crate::session::SessionInfo;
+use crate::utils::HandlerError;
+
+// Most keys tracked at once. Past this the least recently used buckets are evicted, a tenth
+// at a time so the cost is spread over the keys which fill the space again.
+const MAX_BUCKETS: usize = 10_000;
+// How often buckets which have refilled are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+pub struct BucketConfig {
+    // Requests which may be made in a burst
+    pub capacity: f64,
+    // Tokens regained per second
+    pub refill_rate: f64,
+}
+
+impl BucketConfig {
+    // Reads a limit of the form "<requests>/<seconds>", e.g. "20/60"
+    pub fn from_env(name: &str, default: &str) -> Self {
+        let value = dotenv::var(name).unwrap_or_else(|_| default.to_string());
+        let mut parts = value.splitn(2, '/')
+            .map(|p| p.trim().parse::<f64>().ok().filter(|n| *n > 0.0));
+        match (parts.next().flatten(), parts.next().flatten()) {
+            (Some(requests), Some(seconds)) => BucketConfig { capacity: requests, refill_rate: requests / seconds },
+            _ => panic!("{} must be of the form <requests>/<seconds>", name)
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+impl Buckets {
+    fn new() -> Self {
+        Buckets { buckets: HashMap::new(), pruned: Instant::now() }
+    }
+
+    // Full buckets carry no state worth keeping
+    fn prune(&mut self, now: Instant, config: BucketConfig) {
+        self.buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * config.refill_rate < config.capacity);
+        self.pruned = now;
+    }
+
+    // Drops the buckets which have gone longest without a request, which are also those
+    // closest to refilling
+    fn evict_oldest(&mut self, count: usize) {
+        let mut updated = self.buckets.values().map(|b| b.updated).collect::<Vec<_>>();
+        let (_, cutoff, _) = updated.select_nth_unstable(count - 1);
+        let cutoff = *cutoff;
+        self.buckets.retain(|_, b| b.updated > cutoff);
+    }
+}
+
+pub enum KeyBy {
+    // The client address, for anonymous routes. X-Forwarded-For is only believed when the
+    // connection comes from one of the trusted proxies, since anyone can send it.
+    Ip { trusted_proxies: Vec<IpAddr> },
+    // The authenticated user, or device if it has no user yet. Must be wrapped by CheckSession.
+    Session,
+}
+
+impl KeyBy {
+    // TRUSTED_PROXIES lists the addresses of the reverse proxies in front of the server,
+    // comma separated. By default none are trusted.
+    pub fn ip_from_env() -> Self {
+        let value = dotenv::var("TRUSTED_PROXIES").unwrap_or_default();
+        let trusted_proxies = value.split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| addr.parse().expect("TRUSTED_PROXIES must be a comma separated list of IP addresses"))
+            .collect();
+        KeyBy::Ip { trusted_proxies }
+    }
+}
+
+// Each proxy appends the address it was connected from to X-Forwarded-For, so reading from
+// the right, the first address which isn't a trusted proxy's is the client's. Anything to
+// its left was sent by the client, and may be made up.
+fn client_ip(req: &ServiceRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let mut client = req.peer_addr()?.ip();
+    let mut hops = req.headers().get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev();
+    while trusted_proxies.contains(&client) {
+        match hops.next().and_then(|hop| hop.trim().parse().ok()) {
+            Some(hop) => client = hop,
+            None => break,
+        }
+    }
+    Some(client)
+}
+
+#[derive(Clone)]
+pub struct RateLimit {
+    config: BucketConfig,
+    key_by: Arc<KeyBy>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimit {
+    pub fn new(config: BucketConfig, key_by: KeyBy) -> Self {
+        RateLimit {
+            config,
+            key_by: Arc::new(key_by),
+            buckets: Arc::new(Mutex::new(Buckets::new()))
+        }
+    }
+
+    fn key(&self, req: &ServiceRequest) -> Option<String> {
+        match &*self.key_by {
+            KeyBy::Ip { trusted_proxies } => client_ip(req, trusted_proxies).map(|ip| ip.to_string()),
+            KeyBy::Session => req.extensions().get::<SessionInfo>()
+                .map(|s| s.user_id.unwrap_or(s.device_id).to_string()),
+        }
+    }
+
+    // Takes a token for `key`, or returns the number of seconds until one is available
+    fn take(&self, key: String) -> Result<(), u64> {
+        let now = Instant::now();
+        let config = self.config;
+        let mut buckets = self.buckets.lock().expect("Rate limit buckets poisoned");
+
+        if now.duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            buckets.prune(now, config);
+        }
+        if buckets.buckets.len() >= MAX_BUCKETS && !buckets.buckets.contains_key(&key) {
+            buckets.evict_oldest(MAX_BUCKETS / 10);
+        }
+
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket { tokens: config.capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * config.refill_rate)
+            .min(config.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / config.refill_rate).ceil() as u64)
+        }
+    }
+}
+
+impl<S: 'static, B> Transform<S> for RateLimit
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware { service, limit: self.clone() })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limit: RateLimit,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let allowed = match self.limit.key(&req) {
+            Some(key) => self.limit.take(key),
+            None => Ok(())
+        };
+        match allowed {
+            Ok(()) => Box::pin(self.service.call(req)),
+            Err(retry_after) => Box::pin(ready(Ok(req.error_response(HandlerError::RateLimited { retry_after }))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotating_keys_stay_under_the_cap() {
+        // Slow enough that no bucket refills during the test, so none can be pruned
+        let limit = RateLimit::new(BucketConfig { capacity: 2.0, refill_rate: 0.001 }, KeyBy::Session);
+        for i in 0..MAX_BUCKETS * 3 {
+            assert!(limit.take(i.to_string()).is_ok());
+        }
+        assert!(limit.buckets.lock().unwrap().buckets.len() <= MAX_BUCKETS);
+
+        // The most recent keys are still being limited
+        let last = (MAX_BUCKETS * 3 - 1).to_string();
+        assert!(limit.take(last.clone()).is_ok());
+        assert!(limit.take(last).is_err());
+    }
+}
@@ -1,9 +1,22 @@
+table! {
+    attachments (id) {
+        id -> Uuid,
+        uploader -> Uuid,
+        size -> Int8,
+        created -> Timestamptz,
+        expires -> Timestamptz,
+    }
+}
+
 table! {
     devices (id) {
         id -> Uuid,
         user_id -> Nullable<Uuid>,
         missed_messages -> Int4,
         public_key -> Bytea,
+        key_algorithm -> Text,
+        push_provider -> Nullable<Text>,
+        push_token -> Nullable<Text>,
     }
 }
 
@@ -15,6 +28,13 @@ table! {
     }
 }
 
+table! {
+    message_attachments (message_id, attachment_id) {
+        message_id -> Uuid,
+        attachment_id -> Uuid,
+    }
+}
+
 table! {
     messages (id) {
         id -> Uuid,
@@ -22,7 +42,10 @@ table! {
         sender -> Uuid,
         reception_time -> Timestamptz,
         message_type -> Text,
-        payload -> Json,
+        payload -> Nullable<Json>,
+        delivered_at -> Nullable<Timestamptz>,
+        ciphertext -> Nullable<Bytea>,
+        read_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -42,6 +65,16 @@ table! {
     }
 }
 
+table! {
+    uploads (id) {
+        id -> Uuid,
+        uploader -> Uuid,
+        size -> Int8,
+        received -> Int8,
+        updated -> Timestamptz,
+    }
+}
+
 table! {
     users (id) {
         id -> Uuid,
@@ -54,19 +87,28 @@ table! {
         email_verified -> Bool,
         nickname -> Nullable<Text>,
         last_seen -> Nullable<Timestamp>,
+        read_receipts -> Bool,
+        identity_key_algorithm -> Text,
     }
 }
 
+joinable!(attachments -> users (uploader));
 joinable!(devices -> users (user_id));
 joinable!(mailbox -> devices (device_id));
 joinable!(mailbox -> messages (message_id));
+joinable!(message_attachments -> attachments (attachment_id));
+joinable!(message_attachments -> messages (message_id));
 joinable!(onetimekeys -> users (user_id));
+joinable!(uploads -> users (uploader));
 
 allow_tables_to_appear_in_same_query!(
+    attachments,
     devices,
     mailbox,
+    message_attachments,
     messages,
     onetimekeys,
     sessions,
+    uploads,
     users,
 );
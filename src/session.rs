@@ -1,16 +1,13 @@
 use actix_web::{web, FromRequest, HttpRequest, HttpMessage};
 use actix_web::error::BlockingError;
 use chrono::{Duration, NaiveDateTime, Utc};
-use diesel::prelude::*;
 use ring::rand::{SecureRandom, SystemRandom};
-use ring::signature;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use uuid::Uuid;
 
 use crate::{base64enc};
-use crate::utils::{HandlerError, InternalError, Entity};
-
-use super::schema::{devices, sessions};
+use beacon_protocol::v1::session::{DEVICE_ID_HEADER, NONCE_HEADER, SIGNED_NONCE_HEADER, NEW_NONCE_HEADER};
+use crate::utils::{HandlerError, InternalError};
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -19,7 +16,9 @@ use actix_service::{Service, Transform};
 use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
 use futures::future::{ok, Ready, ready};
 use futures::Future;
-use crate::database::{Pool, extract_connection};
+use crate::metrics;
+use crate::storage::{Storage, NonceRotation};
+use std::sync::Arc;
 use std::str::FromStr;
 use actix_web::dev::{PayloadStream, Payload};
 use std::rc::Rc;
@@ -38,37 +37,25 @@ pub struct SessionRequest {
     signed_nonce: Vec<u8>
 }
 
-#[derive(Serialize)]
-pub struct SessionResponse {
-    #[serde(with = "base64enc")]
-    nonce: Vec<u8>
-}
-
-#[derive(Serialize)]
-struct NewSessionResponse {
-    #[serde(with = "base64enc")]
-    nonce: Vec<u8>,
-    expiry: NaiveDateTime
-}
-
 // In seconds
 const SESSION_DURATION: i64 = 60*60;
 
-pub fn new_session_request(pool: &Pool, rng: &SystemRandom) -> Result<Vec<u8>, HandlerError> {
-        let conn = extract_connection(pool)?;
+fn new_nonce(rng: &SystemRandom) -> Result<Vec<u8>, HandlerError> {
+    let mut nonce = vec![0u8; 16];
+    rng.fill(&mut nonce)
+        .map_err(|_e| HandlerError::InternalError{ error: InternalError::RNGError})?;
+    Ok(nonce)
+}
+
+fn expiry() -> NaiveDateTime {
+    (Utc::now() + Duration::seconds(SESSION_DURATION)).naive_utc()
+}
 
+pub fn new_session_request(store: &dyn Storage, rng: &SystemRandom) -> Result<Vec<u8>, HandlerError> {
         // Generate new nonce
-        let mut nonce = vec![0u8; 16];
-        rng.fill(&mut nonce)
-            .map_err(|_e| HandlerError::InternalError{ error: InternalError::RNGError})?;
-
-        // Expiry
-        let expiry = (Utc::now() + Duration::seconds(SESSION_DURATION)).naive_utc();
-        diesel::insert_into(sessions::table)
-            .values((
-                sessions::nonce.eq(&nonce),
-                sessions::expires.eq(expiry)
-            )).execute(&conn).map_err(|e| -> HandlerError { InternalError::DatabaseError(e).into()})?;
+        let nonce = new_nonce(rng)?;
+        store.create_session(&nonce, expiry())?;
+        metrics::SESSIONS_CREATED.inc();
         // Return new nonce
         Ok(nonce)
 }
@@ -91,50 +78,23 @@ impl FromRequest for SessionInfo {
     }
 }
 
-fn check_session(req: SessionRequest, pool: &Pool, rng: &SystemRandom) -> Result<(Uuid, Option<Uuid>, Vec<u8>), HandlerError> {
-    let conn = extract_connection(pool)?;
-
-    // Look for active session
-    let (session_id, session_expires) = sessions::table.filter(sessions::nonce.eq(&req.nonce))
-        .select((sessions::id, sessions::expires))
-        .first::<(i32, NaiveDateTime)>(&conn)
-        .map_err(|e| match e {
-            diesel::result::Error::NotFound => HandlerError::SessionInvalid,
-            _ => HandlerError::InternalError {error: InternalError::DatabaseError(e)}
-        })?;
-
-    // Still valid
-    return if session_expires > Utc::now().naive_utc() {
-        // Query devices
-        let (pub_key, owner) = devices::table.find(req.device_id).select((devices::public_key, devices::user_id)).first::<(Vec<u8>, Option<Uuid>)>(&conn)
-            .map_err(|e| -> HandlerError { match e {
-                diesel::result::Error::NotFound => HandlerError::UnknownEntity {entity: Entity::Device {uuid: req.device_id}},
-                _ => InternalError::DatabaseError(e).into()
-            }})?;
-        let device_public_key = signature::UnparsedPublicKey::new(&signature::ED25519, pub_key);
-        device_public_key.verify(&req.nonce[..], &req.signed_nonce[..])
-            .map_err(|_e| HandlerError::AuthenticationError)?;
-
-        // Now generate new nonce
-        let mut new_nonce =  vec![0u8; 16];
-        rng.fill(&mut new_nonce)
-            .map_err(|_e| InternalError::RNGError)?;
-
-        // And expiry
-        let expiry = (Utc::now() + Duration::seconds(SESSION_DURATION)).naive_utc();
-        diesel::update(sessions::table.find(session_id))
-            .set((
-                sessions::nonce.eq(&new_nonce),
-                sessions::expires.eq(expiry)
-            )).execute(&conn).map_err(|e| InternalError::DatabaseError(e))?;
-
-        // Return new nonce, and device id
-        Ok((req.device_id, owner, new_nonce))
-    } else {
-        diesel::delete(sessions::table.find(session_id))
-            .execute(&conn).map_err(|e| InternalError::DatabaseError(e))?;
-        Err(HandlerError::SessionInvalid)
-    }
+// A replacement for `nonce`, which is consumed by the request it authenticated
+pub fn nonce_rotation(nonce: Vec<u8>, rng: &SystemRandom) -> Result<NonceRotation, HandlerError> {
+    Ok(NonceRotation { nonce, new_nonce: new_nonce(rng)?, expires: expiry() })
+}
+
+fn check_session(req: SessionRequest, store: &dyn Storage, rng: &SystemRandom) -> Result<(Uuid, Option<Uuid>, Vec<u8>), HandlerError> {
+    // Query devices
+    let device = store.device_key(req.device_id)?;
+    device.algorithm.verify(&device.public_key, &req.nonce[..], &req.signed_nonce[..])
+        .map_err(|_e| HandlerError::AuthenticationError)?;
+
+    // Now swap the session's nonce for a new one, failing if it isn't active
+    let rotation = nonce_rotation(req.nonce, rng)?;
+    store.rotate_nonce(&rotation)?;
+
+    // Return new nonce, and device id
+    Ok((req.device_id, device.user_id, rotation.new_nonce))
 }
 
 fn extract_header_data (req: &ServiceRequest) -> Result<SessionRequest, HandlerError> {
@@ -143,12 +103,12 @@ fn extract_header_data (req: &ServiceRequest) -> Result<SessionRequest, HandlerE
         .ok_or(head_err(n))
         .and_then(|header| header.to_str().map_err(|_e| head_err(n)))};
 
-    let device_id = uuid::Uuid::from_str(get_header("X-DEVICEID")?)
-        .map_err(|_e| head_err("X-DEVICEID"))?;
-    let nonce = base64::decode(get_header("X-NONCE")?)
-        .map_err(|_e| head_err("X-NONCE"))?;
-    let signed_nonce = base64::decode(get_header("X-SIGNEDNONCE")?)
-        .map_err(|_e| head_err("X-SIGNEDNONCE"))?;
+    let device_id = uuid::Uuid::from_str(get_header(DEVICE_ID_HEADER)?)
+        .map_err(|_e| head_err(DEVICE_ID_HEADER))?;
+    let nonce = base64::decode(get_header(NONCE_HEADER)?)
+        .map_err(|_e| head_err(NONCE_HEADER))?;
+    let signed_nonce = base64::decode(get_header(SIGNED_NONCE_HEADER)?)
+        .map_err(|_e| head_err(SIGNED_NONCE_HEADER))?;
 
     Ok(SessionRequest {
         device_id,
@@ -199,18 +159,23 @@ impl<S, B> Service for CheckSessionMiddleware<S>
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
         let session_data = extract_header_data(&req);
-        let pool = req.app_data::<Pool>().ok_or(InternalError::ServerDataError);
+        let store = req.app_data::<Arc<dyn Storage>>().ok_or(InternalError::ServerDataError);
         let rng = req.app_data::<SystemRandom>().ok_or(InternalError::ServerDataError);
         let mut srv = self.service.clone();
         Box::pin(async move {
-            let (device_id, user_id, nonce) = web::block(move || check_session(session_data?, &pool?.into_inner(), &rng?.into_inner()))
+            let checked = web::block(move || check_session(session_data?, store?.get_ref().as_ref(), &rng?.into_inner()))
                 .await.map_err(|e| match e {
                 BlockingError::Error(he) => he,
                 BlockingError::Canceled => InternalError::AsyncError.into()
-            })?;
+            });
+            // Answered rather than failed, so outer middleware can still respond for the request
+            let (device_id, user_id, nonce) = match checked {
+                Ok(session) => session,
+                Err(he) => return Ok(req.error_response(he)),
+            };
             req.extensions_mut().insert(SessionInfo{ device_id, user_id });
             let mut res: Self::Response = srv.call(req).await?;
-            res.headers_mut().insert(HeaderName::try_from("x-newnonce").map_err(|_e| HandlerError::from(InternalError::JustAnError))?,
+            res.headers_mut().insert(HeaderName::try_from(NEW_NONCE_HEADER).map_err(|_e| HandlerError::from(InternalError::JustAnError))?,
                                      HeaderValue::try_from(base64::encode(&nonce)).expect("NONCE BASE64 INVALID"));
 
             Ok(res)
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use uuid::Uuid;
+use crate::crypto::KeyAlgorithm;
+use crate::message::{MailboxReturn, NewMessage};
+use beacon_protocol::v1::session::PushRegistration;
+use beacon_protocol::v1::user::UserCreation;
+use crate::utils::{HandlerError, Entity};
+use super::{Storage, NonceRotation, DeviceKey, PushTarget, UserKeys, Delivered, NewAttachment, UploadState, SchemaVersion};
+
+// Keeps everything in process memory, for tests and local development. Every
+// operation holds a single lock, which makes each one trivially atomic.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>
+}
+
+#[derive(Default)]
+struct MemoryState {
+    // Session expiry by nonce
+    sessions: HashMap<Vec<u8>, NaiveDateTime>,
+    devices: HashMap<Uuid, DeviceKey>,
+    push_tokens: HashMap<Uuid, PushRegistration>,
+    users: HashMap<Uuid, MemoryUser>,
+    onetime_keys: HashMap<Uuid, Vec<Vec<u8>>>,
+    messages: HashMap<Uuid, MemoryMessage>,
+    // (device, message) in the order messages arrived
+    mailbox: Vec<(Uuid, Uuid)>,
+    // Messages counted as missed by each device since it last emptied its mailbox
+    missed: HashMap<Uuid, i32>,
+    attachments: HashMap<Uuid, MemoryAttachment>,
+    uploads: HashMap<Uuid, MemoryUpload>,
+}
+
+struct MemoryUser {
+    email: String,
+    keys: UserKeys,
+    read_receipts: bool,
+}
+
+struct MemoryMessage {
+    recipient: Uuid,
+    sender: Uuid,
+    message_type: String,
+    timestamp: DateTime<Utc>,
+    payload: Option<serde_json::Value>,
+    ciphertext: Option<Vec<u8>>,
+    delivered: bool,
+    read: bool,
+    attachments: Vec<Uuid>,
+}
+
+struct MemoryAttachment {
+    uploader: Uuid,
+    expires: DateTime<Utc>,
+}
+
+struct MemoryUpload {
+    uploader: Uuid,
+    state: UploadState,
+    updated: DateTime<Utc>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryState> {
+        // A panic mid-operation can only have happened before anything was modified
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn unknown_user(user_id: Uuid) -> HandlerError {
+    HandlerError::UnknownEntity { entity: Entity::User { uuid: user_id } }
+}
+
+impl MemoryState {
+    fn rotate_nonce(&mut self, rotation: &NonceRotation) -> Result<(), HandlerError> {
+        match self.sessions.remove(&rotation.nonce) {
+            Some(expires) if expires > Utc::now().naive_utc() => {
+                self.sessions.insert(rotation.new_nonce.clone(), rotation.expires);
+                Ok(())
+            },
+            _ => Err(HandlerError::SessionInvalid)
+        }
+    }
+
+    fn user_mut(&mut self, user_id: Uuid) -> Result<&mut MemoryUser, HandlerError> {
+        self.users.get_mut(&user_id).ok_or_else(|| unknown_user(user_id))
+    }
+
+    fn insert_attachment(&mut self, attachment: &NewAttachment) {
+        self.attachments.insert(attachment.id, MemoryAttachment { uploader: attachment.uploader, expires: attachment.expires });
+    }
+
+    fn insert_message(&mut self, msg: &NewMessage) -> Uuid {
+        let message_id = Uuid::new_v4();
+        self.messages.insert(message_id, MemoryMessage {
+            recipient: msg.recipient,
+            sender: msg.sender,
+            message_type: msg.message_type.clone(),
+            timestamp: Utc::now(),
+            payload: msg.payload.clone(),
+            ciphertext: msg.ciphertext.clone(),
+            delivered: false,
+            read: false,
+            attachments: msg.attachments.clone(),
+        });
+        let recipient_devices = self.devices.iter()
+            .filter(|(_, device)| device.user_id == Some(msg.recipient))
+            .map(|(device_id, _)| (*device_id, message_id))
+            .collect::<Vec<_>>();
+        if msg.counts_as_missed() {
+            for (device_id, _) in &recipient_devices {
+                *self.missed.entry(*device_id).or_default() += 1;
+            }
+        }
+        self.mailbox.extend(recipient_devices);
+        message_id
+    }
+
+    fn upload_mut(&mut self, upload_id: Uuid, uploader: Uuid) -> Result<&mut MemoryUpload, HandlerError> {
+        self.uploads.get_mut(&upload_id)
+            .filter(|upload| upload.uploader == uploader)
+            .ok_or(HandlerError::UnknownEntity { entity: Entity::Upload { uuid: upload_id } })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create_session(&self, nonce: &[u8], expires: NaiveDateTime) -> Result<(), HandlerError> {
+        self.lock().sessions.insert(nonce.to_vec(), expires);
+        Ok(())
+    }
+
+    fn rotate_nonce(&self, rotation: &NonceRotation) -> Result<(), HandlerError> {
+        self.lock().rotate_nonce(rotation)
+    }
+
+    fn create_device(&self, public_key: &[u8], algorithm: KeyAlgorithm, rotation: &NonceRotation) -> Result<Uuid, HandlerError> {
+        let mut state = self.lock();
+        state.rotate_nonce(rotation)?;
+        let device_id = Uuid::new_v4();
+        state.devices.insert(device_id, DeviceKey { public_key: public_key.to_vec(), algorithm, user_id: None });
+        Ok(device_id)
+    }
+
+    fn device_key(&self, device_id: Uuid) -> Result<DeviceKey, HandlerError> {
+        self.lock().devices.get(&device_id).cloned()
+            .ok_or(HandlerError::UnknownEntity { entity: Entity::Device { uuid: device_id } })
+    }
+
+    fn set_push_token(&self, device_id: Uuid, push: Option<&PushRegistration>) -> Result<(), HandlerError> {
+        let mut state = self.lock();
+        match push {
+            Some(push) => state.push_tokens.insert(device_id, push.clone()),
+            None => state.push_tokens.remove(&device_id),
+        };
+        Ok(())
+    }
+
+    fn clear_push_token_if(&self, device_id: Uuid, token: &str) -> Result<(), HandlerError> {
+        let mut state = self.lock();
+        if state.push_tokens.get(&device_id).is_some_and(|push| push.token == token) {
+            state.push_tokens.remove(&device_id);
+        }
+        Ok(())
+    }
+
+    fn push_targets(&self, user_id: Uuid) -> Result<Vec<PushTarget>, HandlerError> {
+        let state = self.lock();
+        Ok(state.push_tokens.iter()
+            .filter(|(device_id, _)| state.devices.get(device_id).and_then(|device| device.user_id) == Some(user_id))
+            .map(|(device_id, push)| PushTarget {
+                device_id: *device_id,
+                push: push.clone(),
+                missed_messages: state.missed.get(device_id).copied().unwrap_or(0),
+            })
+            .collect())
+    }
+
+    fn create_user(&self, user: &UserCreation, device_id: Uuid) -> Result<Uuid, HandlerError> {
+        let mut state = self.lock();
+        if state.users.values().any(|existing| existing.email == user.email) {
+            return Err(HandlerError::RecordMustBeUnique { name: "email".to_string() });
+        }
+        let user_id = Uuid::new_v4();
+        state.users.insert(user_id, MemoryUser {
+            email: user.email.clone(),
+            keys: UserKeys {
+                identity_key: user.identity_key.clone(),
+                identity_key_algorithm: user.identity_key_algorithm,
+                signed_prekey: user.signed_prekey.clone(),
+                prekey_signature: user.prekey_signature.clone(),
+            },
+            read_receipts: true,
+        });
+        if let Some(device) = state.devices.get_mut(&device_id) {
+            device.user_id = Some(user_id);
+        }
+        Ok(user_id)
+    }
+
+    fn user_keys(&self, user_id: Uuid) -> Result<UserKeys, HandlerError> {
+        Ok(self.lock().user_mut(user_id)?.keys.clone())
+    }
+
+    fn update_prekey(&self, user_id: Uuid, signed_prekey: &[u8], prekey_signature: &[u8]) -> Result<(), HandlerError> {
+        let mut state = self.lock();
+        let keys = &mut state.user_mut(user_id)?.keys;
+        keys.signed_prekey = signed_prekey.to_vec();
+        keys.prekey_signature = prekey_signature.to_vec();
+        Ok(())
+    }
+
+    fn reset_identity(&self, user_id: Uuid, keys: &UserKeys, notices: &dyn Fn(&[Uuid]) -> Vec<NewMessage>) -> Result<Vec<Uuid>, HandlerError> {
+        let mut state = self.lock();
+        state.user_mut(user_id)?.keys = keys.clone();
+        state.onetime_keys.remove(&user_id);
+
+        let mut correspondents = state.messages.values()
+            .filter_map(|msg| match (msg.sender == user_id, msg.recipient == user_id) {
+                (true, false) => Some(msg.recipient),
+                (false, true) => Some(msg.sender),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+        correspondents.sort();
+        correspondents.dedup();
+        for notice in &notices(&correspondents) {
+            state.insert_message(notice);
+        }
+        Ok(correspondents)
+    }
+
+    fn read_receipts(&self, user_id: Uuid) -> Result<bool, HandlerError> {
+        Ok(self.lock().user_mut(user_id)?.read_receipts)
+    }
+
+    fn set_read_receipts(&self, user_id: Uuid, read_receipts: bool) -> Result<(), HandlerError> {
+        self.lock().user_mut(user_id)?.read_receipts = read_receipts;
+        Ok(())
+    }
+
+    fn add_onetime_keys(&self, user_id: Uuid, keys: &[Vec<u8>]) -> Result<usize, HandlerError> {
+        self.lock().onetime_keys.entry(user_id).or_default().extend_from_slice(keys);
+        Ok(keys.len())
+    }
+
+    fn take_onetime_key(&self, user_id: Uuid) -> Result<Option<Vec<u8>>, HandlerError> {
+        Ok(self.lock().onetime_keys.get_mut(&user_id).and_then(|keys| keys.pop()))
+    }
+
+    fn insert_messages(&self, msgs: &[NewMessage]) -> Result<Vec<Uuid>, HandlerError> {
+        let mut state = self.lock();
+        Ok(msgs.iter().map(|msg| state.insert_message(msg)).collect())
+    }
+
+    fn take_mailbox(&self, device_id: Uuid, receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<Vec<MailboxReturn>, HandlerError> {
+        let mut state = self.lock();
+        let (collected, remaining) = state.mailbox.drain(..)
+            .partition::<Vec<_>, _>(|(device, _)| *device == device_id);
+        state.mailbox = remaining;
+        state.missed.remove(&device_id);
+
+        let mut delivered = Vec::new();
+        for (_, message_id) in &collected {
+            if let Some(msg) = state.messages.get_mut(message_id).filter(|msg| !msg.delivered) {
+                msg.delivered = true;
+                delivered.push(Delivered {
+                    message_id: *message_id,
+                    message_type: msg.message_type.clone(),
+                    sender: msg.sender,
+                    recipient: msg.recipient,
+                });
+            }
+        }
+        for receipt in receipts(&delivered) {
+            state.insert_message(&receipt);
+        }
+
+        let mut messages = collected.iter()
+            .filter_map(|(_, message_id)| state.messages.get(message_id).map(|msg| MailboxReturn {
+                id: *message_id,
+                sender: msg.sender,
+                message_type: msg.message_type.clone(),
+                timestamp: msg.timestamp,
+                payload: msg.payload.clone(),
+                ciphertext: msg.ciphertext.clone(),
+            }))
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|msg| msg.timestamp);
+        Ok(messages)
+    }
+
+    fn mark_read(&self, recipient: Uuid, message_ids: &[Uuid], receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<usize, HandlerError> {
+        let mut state = self.lock();
+        let mut read = Vec::new();
+        for message_id in message_ids {
+            if let Some(msg) = state.messages.get_mut(message_id).filter(|msg| msg.recipient == recipient && !msg.read) {
+                msg.read = true;
+                read.push(Delivered {
+                    message_id: *message_id,
+                    message_type: msg.message_type.clone(),
+                    sender: msg.sender,
+                    recipient,
+                });
+            }
+        }
+        let receipts = receipts(&read);
+        for receipt in &receipts {
+            state.insert_message(receipt);
+        }
+        Ok(receipts.len())
+    }
+
+    fn mailbox_backlog(&self) -> Result<i64, HandlerError> {
+        Ok(self.lock().mailbox.len() as i64)
+    }
+
+    fn insert_attachment(&self, attachment: &NewAttachment) -> Result<(), HandlerError> {
+        self.lock().insert_attachment(attachment);
+        Ok(())
+    }
+
+    fn attachment_readable(&self, attachment_id: Uuid, user_id: Uuid) -> Result<bool, HandlerError> {
+        let state = self.lock();
+        Ok(match state.attachments.get(&attachment_id).filter(|attachment| attachment.expires > Utc::now()) {
+            None => false,
+            Some(attachment) if attachment.uploader == user_id => true,
+            Some(_) => state.messages.values()
+                .any(|msg| msg.recipient == user_id && msg.attachments.contains(&attachment_id)),
+        })
+    }
+
+    fn owned_attachments(&self, uploader: Uuid, attachment_ids: &[Uuid]) -> Result<Vec<Uuid>, HandlerError> {
+        let state = self.lock();
+        let now = Utc::now();
+        Ok(attachment_ids.iter()
+            .filter(|attachment_id| state.attachments.get(attachment_id)
+                .is_some_and(|attachment| attachment.uploader == uploader && attachment.expires > now))
+            .cloned()
+            .collect())
+    }
+
+    fn delete_attachment(&self, attachment_id: Uuid) -> Result<(), HandlerError> {
+        self.lock().attachments.remove(&attachment_id);
+        Ok(())
+    }
+
+    fn expire_attachments(&self) -> Result<Vec<Uuid>, HandlerError> {
+        let mut state = self.lock();
+        let now = Utc::now();
+        let expired = state.attachments.iter()
+            .filter(|(_, attachment)| attachment.expires <= now)
+            .map(|(attachment_id, _)| *attachment_id)
+            .collect::<Vec<_>>();
+        for attachment_id in &expired {
+            state.attachments.remove(attachment_id);
+        }
+        Ok(expired)
+    }
+
+    fn create_upload(&self, uploader: Uuid, size: i64) -> Result<Uuid, HandlerError> {
+        let upload_id = Uuid::new_v4();
+        self.lock().uploads.insert(upload_id, MemoryUpload {
+            uploader,
+            state: UploadState { size, received: 0 },
+            updated: Utc::now(),
+        });
+        Ok(upload_id)
+    }
+
+    fn upload_state(&self, upload_id: Uuid, uploader: Uuid) -> Result<UploadState, HandlerError> {
+        Ok(self.lock().upload_mut(upload_id, uploader)?.state)
+    }
+
+    fn advance_upload(&self, upload_id: Uuid, uploader: Uuid, write: &mut dyn FnMut(UploadState) -> Result<i64, HandlerError>) -> Result<UploadState, HandlerError> {
+        let mut state = self.lock();
+        let upload = state.upload_mut(upload_id, uploader)?;
+        upload.state.received = write(upload.state)?;
+        upload.updated = Utc::now();
+        Ok(upload.state)
+    }
+
+    fn finish_upload(&self, upload_id: Uuid, uploader: Uuid, attachment: &NewAttachment, finish: &mut dyn FnMut(UploadState) -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        let mut state = self.lock();
+        finish(state.upload_mut(upload_id, uploader)?.state)?;
+        state.uploads.remove(&upload_id);
+        state.insert_attachment(attachment);
+        Ok(())
+    }
+
+    fn expire_uploads(&self, cutoff: DateTime<Utc>) -> Result<Vec<Uuid>, HandlerError> {
+        let mut state = self.lock();
+        let expired = state.uploads.iter()
+            .filter(|(_, upload)| upload.updated <= cutoff)
+            .map(|(upload_id, _)| *upload_id)
+            .collect::<Vec<_>>();
+        for upload_id in &expired {
+            state.uploads.remove(upload_id);
+        }
+        Ok(expired)
+    }
+
+    // There is nothing to migrate, so memory is always up to date
+    fn schema_version(&self) -> Result<SchemaVersion, HandlerError> {
+        Ok(SchemaVersion { expected: "memory", found: Some("memory".to_string()) })
+    }
+}
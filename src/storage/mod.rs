@@ -0,0 +1,151 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use uuid::Uuid;
+use crate::crypto::KeyAlgorithm;
+use crate::message::{MailboxReturn, NewMessage};
+use beacon_protocol::v1::session::PushRegistration;
+use beacon_protocol::v1::user::UserCreation;
+use crate::utils::HandlerError;
+
+mod memory;
+mod postgres;
+mod sqlite;
+mod sqlite_schema;
+
+pub use self::memory::MemoryStorage;
+pub use self::postgres::PgStorage;
+pub use self::sqlite::SqliteStorage;
+
+// Persistence for everything except attachment blobs, which live in a BlobStore.
+// Each method is atomic; the validation around it (signatures, message types,
+// expiry policy) stays with the callers so every backend enforces the same rules.
+// Where an operation must do work of the caller's while the data is locked, it
+// takes a closure, which must not call back into the storage.
+pub trait Storage: Send + Sync {
+    fn create_session(&self, nonce: &[u8], expires: NaiveDateTime) -> Result<(), HandlerError>;
+    // Replaces the nonce of a live session. Fails with SessionInvalid if there is no session
+    // holding the nonce, removing it if it has expired.
+    fn rotate_nonce(&self, rotation: &NonceRotation) -> Result<(), HandlerError>;
+
+    // Registers a device and rotates the nonce of the session it was registered through
+    fn create_device(&self, public_key: &[u8], algorithm: KeyAlgorithm, rotation: &NonceRotation) -> Result<Uuid, HandlerError>;
+    fn device_key(&self, device_id: Uuid) -> Result<DeviceKey, HandlerError>;
+    // Sets the token through which a device is woken, or removes it
+    fn set_push_token(&self, device_id: Uuid, push: Option<&PushRegistration>) -> Result<(), HandlerError>;
+    // Removes a device's push token if it is still `token`, so one the device registered
+    // since is kept
+    fn clear_push_token_if(&self, device_id: Uuid, token: &str) -> Result<(), HandlerError>;
+    // The user's devices which have a push token, with how many messages are waiting for each
+    fn push_targets(&self, user_id: Uuid) -> Result<Vec<PushTarget>, HandlerError>;
+
+    // Creates a user and makes it the owner of `device_id`
+    fn create_user(&self, user: &UserCreation, device_id: Uuid) -> Result<Uuid, HandlerError>;
+    fn user_keys(&self, user_id: Uuid) -> Result<UserKeys, HandlerError>;
+    fn update_prekey(&self, user_id: Uuid, signed_prekey: &[u8], prekey_signature: &[u8]) -> Result<(), HandlerError>;
+    // Replaces every key of a user, discarding their one-time keys. Everyone the user has
+    // exchanged messages with is passed to `notices`, whose messages are inserted before
+    // returning them.
+    fn reset_identity(&self, user_id: Uuid, keys: &UserKeys, notices: &dyn Fn(&[Uuid]) -> Vec<NewMessage>) -> Result<Vec<Uuid>, HandlerError>;
+    fn read_receipts(&self, user_id: Uuid) -> Result<bool, HandlerError>;
+    fn set_read_receipts(&self, user_id: Uuid, read_receipts: bool) -> Result<(), HandlerError>;
+
+    fn add_onetime_keys(&self, user_id: Uuid, keys: &[Vec<u8>]) -> Result<usize, HandlerError>;
+    // Removes and returns one of the user's one-time keys, if any are left
+    fn take_onetime_key(&self, user_id: Uuid) -> Result<Option<Vec<u8>>, HandlerError>;
+
+    // Stores messages and places each in the mailbox of every device owned by its recipient.
+    // Those which count as missed are counted until the device next empties its mailbox.
+    fn insert_messages(&self, msgs: &[NewMessage]) -> Result<Vec<Uuid>, HandlerError>;
+    // Empties a device's mailbox. Messages collected for the first time are marked delivered
+    // and passed to `receipts`, whose messages are inserted before the mailbox is returned.
+    fn take_mailbox(&self, device_id: Uuid, receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<Vec<MailboxReturn>, HandlerError>;
+    // Marks those of `message_ids` addressed to `recipient` as read. Messages read for the first
+    // time are passed to `receipts`, whose messages are inserted before returning their number.
+    fn mark_read(&self, recipient: Uuid, message_ids: &[Uuid], receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<usize, HandlerError>;
+    fn mailbox_backlog(&self) -> Result<i64, HandlerError>;
+
+    fn insert_attachment(&self, attachment: &NewAttachment) -> Result<(), HandlerError>;
+    // Whether the attachment is still kept and `user_id` uploaded it, or has been sent a
+    // message referencing it
+    fn attachment_readable(&self, attachment_id: Uuid, user_id: Uuid) -> Result<bool, HandlerError>;
+    // Those of `attachment_ids` which are still kept and were uploaded by `uploader`
+    fn owned_attachments(&self, uploader: Uuid, attachment_ids: &[Uuid]) -> Result<Vec<Uuid>, HandlerError>;
+    fn delete_attachment(&self, attachment_id: Uuid) -> Result<(), HandlerError>;
+    // Removes attachments past their expiry, returning their ids
+    fn expire_attachments(&self) -> Result<Vec<Uuid>, HandlerError>;
+
+    fn create_upload(&self, uploader: Uuid, size: i64) -> Result<Uuid, HandlerError>;
+    fn upload_state(&self, upload_id: Uuid, uploader: Uuid) -> Result<UploadState, HandlerError>;
+    // Locks the upload while `write` stores a chunk, then records the new number of bytes received
+    fn advance_upload(&self, upload_id: Uuid, uploader: Uuid, write: &mut dyn FnMut(UploadState) -> Result<i64, HandlerError>) -> Result<UploadState, HandlerError>;
+    // Locks the upload while `finish` checks and commits its blob, then replaces it with `attachment`
+    fn finish_upload(&self, upload_id: Uuid, uploader: Uuid, attachment: &NewAttachment, finish: &mut dyn FnMut(UploadState) -> Result<(), HandlerError>) -> Result<(), HandlerError>;
+    // Removes uploads which have not progressed since `cutoff`, returning their ids
+    fn expire_uploads(&self, cutoff: DateTime<Utc>) -> Result<Vec<Uuid>, HandlerError>;
+
+    // Err if the database can't be reached
+    fn schema_version(&self) -> Result<SchemaVersion, HandlerError>;
+    // For backends with a connection pool
+    fn connection_stats(&self) -> Option<ConnectionStats> {
+        None
+    }
+}
+
+pub struct NonceRotation {
+    pub nonce: Vec<u8>,
+    pub new_nonce: Vec<u8>,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Clone)]
+pub struct DeviceKey {
+    pub public_key: Vec<u8>,
+    pub algorithm: KeyAlgorithm,
+    pub user_id: Option<Uuid>,
+}
+
+#[derive(Clone)]
+pub struct UserKeys {
+    pub identity_key: Vec<u8>,
+    pub identity_key_algorithm: KeyAlgorithm,
+    pub signed_prekey: Vec<u8>,
+    pub prekey_signature: Vec<u8>,
+}
+
+pub struct PushTarget {
+    pub device_id: Uuid,
+    pub push: PushRegistration,
+    pub missed_messages: i32,
+}
+
+pub struct Delivered {
+    pub message_id: Uuid,
+    pub message_type: String,
+    pub sender: Uuid,
+    pub recipient: Uuid,
+}
+
+pub struct NewAttachment {
+    pub id: Uuid,
+    pub uploader: Uuid,
+    pub size: i64,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy)]
+pub struct UploadState {
+    pub size: i64,
+    pub received: i64,
+}
+
+pub struct SchemaVersion {
+    // Newest migration embedded in this build
+    pub expected: &'static str,
+    // Newest migration applied to the database
+    pub found: Option<String>,
+}
+
+pub struct ConnectionStats {
+    pub connections: u32,
+    pub idle: u32,
+    pub max: u32,
+}
@@ -0,0 +1,506 @@
+use std::time::Duration;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::pg::expression::array_comparison::any;
+use diesel::prelude::*;
+use diesel_migrations::MigrationConnection;
+use uuid::Uuid;
+use crate::crypto::KeyAlgorithm;
+use crate::database::{self, Pool, Conn, extract_connection};
+use crate::message::{MailboxReturn, NewMessage};
+use crate::migrate;
+use crate::schema::{attachments, devices, mailbox, message_attachments, messages, onetimekeys, sessions, uploads, users};
+use beacon_protocol::v1::session::PushRegistration;
+use beacon_protocol::v1::user::UserCreation;
+use crate::utils::{HandlerError, InternalError, Entity};
+use super::{Storage, NonceRotation, DeviceKey, PushTarget, UserKeys, Delivered, NewAttachment, UploadState, SchemaVersion, ConnectionStats};
+
+pub struct PgStorage {
+    pool: Pool
+}
+
+impl PgStorage {
+    // Connects to DATABASE_URL, bringing its schema up to date
+    pub fn open() -> Result<Self, String> {
+        let pool = database::obtain_pool();
+        let conn = pool.get().map_err(|e| format!("Failed to connect to the database: {}", e))?;
+        migrate::POSTGRES.check_schema(&conn)?;
+        Ok(PgStorage { pool })
+    }
+
+    fn conn(&self) -> Result<Conn, HandlerError> {
+        extract_connection(&self.pool)
+    }
+}
+
+fn unknown_user(user_id: Uuid) -> HandlerError {
+    HandlerError::UnknownEntity { entity: Entity::User { uuid: user_id } }
+}
+
+// Maps NotFound onto the entity which was being looked up
+fn not_found(entity: Entity) -> impl FnOnce(diesel::result::Error) -> HandlerError {
+    move |e| match e {
+        diesel::result::Error::NotFound => HandlerError::UnknownEntity { entity },
+        _ => InternalError::DatabaseError(e).into()
+    }
+}
+
+fn rotate_nonce(conn: &Conn, rotation: &NonceRotation) -> Result<(), HandlerError> {
+    let (session_id, expires) = sessions::table.filter(sessions::nonce.eq(&rotation.nonce))
+        .select((sessions::id, sessions::expires))
+        .first::<(i32, NaiveDateTime)>(conn)
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => HandlerError::SessionInvalid,
+            _ => InternalError::DatabaseError(e).into()
+        })?;
+
+    if expires <= Utc::now().naive_utc() {
+        diesel::delete(sessions::table.find(session_id)).execute(conn)?;
+        return Err(HandlerError::SessionInvalid);
+    }
+    diesel::update(sessions::table.find(session_id))
+        .set((
+            sessions::nonce.eq(&rotation.new_nonce),
+            sessions::expires.eq(rotation.expires)
+        )).execute(conn)?;
+    Ok(())
+}
+
+fn insert_message(conn: &Conn, msg: &NewMessage) -> QueryResult<Uuid> {
+    let device_ids: Vec<Uuid> = match msg.counts_as_missed() {
+        true => diesel::update(devices::table.filter(devices::user_id.eq(msg.recipient)))
+            .set(devices::missed_messages.eq(devices::missed_messages + 1))
+            .returning(devices::id)
+            .get_results::<Uuid>(conn)?,
+        false => devices::table.filter(devices::user_id.eq(msg.recipient))
+            .select(devices::id)
+            .load::<Uuid>(conn)?,
+    };
+
+    let message_id = diesel::insert_into(messages::table)
+        .values((
+            messages::recipient.eq(msg.recipient),
+            messages::message_type.eq(&msg.message_type),
+            messages::sender.eq(msg.sender),
+            messages::payload.eq(&msg.payload),
+            messages::ciphertext.eq(&msg.ciphertext),
+        ))
+        .returning(messages::id)
+        .get_result::<Uuid>(conn)?;
+
+    let links: Vec<_> = msg.attachments.iter()
+        .map(|x| (message_attachments::message_id.eq(message_id), message_attachments::attachment_id.eq(x)))
+        .collect();
+    diesel::insert_into(message_attachments::table)
+        .values(&links)
+        .execute(conn)?;
+
+    let mbox_messages: Vec<_> = device_ids.iter().map(|x| (mailbox::device_id.eq(x), mailbox::message_id.eq(message_id))).collect();
+
+    diesel::insert_into(mailbox::table)
+        .values(&mbox_messages)
+        .execute(conn)?;
+    Ok(message_id)
+}
+
+fn insert_messages(conn: &Conn, msgs: &[NewMessage]) -> QueryResult<Vec<Uuid>> {
+    msgs.iter().map(|msg| insert_message(conn, msg)).collect()
+}
+
+fn insert_attachment(conn: &Conn, attachment: &NewAttachment) -> QueryResult<usize> {
+    diesel::insert_into(attachments::table)
+        .values((
+            attachments::id.eq(attachment.id),
+            attachments::uploader.eq(attachment.uploader),
+            attachments::size.eq(attachment.size),
+            attachments::expires.eq(attachment.expires)
+        ))
+        .execute(conn)
+}
+
+// Locks an upload belonging to `uploader` for the rest of the transaction
+fn lock_upload(conn: &Conn, upload_id: Uuid, uploader: Uuid) -> Result<UploadState, HandlerError> {
+    let (size, received) = uploads::table.find(upload_id)
+        .filter(uploads::uploader.eq(uploader))
+        .select((uploads::size, uploads::received))
+        .for_update()
+        .first::<(i64, i64)>(conn)
+        .map_err(not_found(Entity::Upload { uuid: upload_id }))?;
+    Ok(UploadState { size, received })
+}
+
+impl Storage for PgStorage {
+    fn create_session(&self, nonce: &[u8], expires: NaiveDateTime) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::insert_into(sessions::table)
+            .values((
+                sessions::nonce.eq(nonce),
+                sessions::expires.eq(expires)
+            )).execute(&conn)?;
+        Ok(())
+    }
+
+    fn rotate_nonce(&self, rotation: &NonceRotation) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        rotate_nonce(&conn, rotation)
+    }
+
+    fn create_device(&self, public_key: &[u8], algorithm: KeyAlgorithm, rotation: &NonceRotation) -> Result<Uuid, HandlerError> {
+        let conn = self.conn()?;
+        conn.transaction::<_, HandlerError, _>(|| {
+            rotate_nonce(&conn, rotation)?;
+            Ok(diesel::insert_into(devices::table)
+                .values((
+                    devices::public_key.eq(public_key),
+                    devices::key_algorithm.eq(algorithm)
+                ))
+                .returning(devices::id)
+                .get_result::<Uuid>(&conn)?)
+        })
+    }
+
+    fn device_key(&self, device_id: Uuid) -> Result<DeviceKey, HandlerError> {
+        let conn = self.conn()?;
+        let (public_key, algorithm, user_id) = devices::table.find(device_id)
+            .select((devices::public_key, devices::key_algorithm, devices::user_id))
+            .first::<(Vec<u8>, KeyAlgorithm, Option<Uuid>)>(&conn)
+            .map_err(not_found(Entity::Device { uuid: device_id }))?;
+        Ok(DeviceKey { public_key, algorithm, user_id })
+    }
+
+    fn set_push_token(&self, device_id: Uuid, push: Option<&PushRegistration>) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::update(devices::table.find(device_id))
+            .set((
+                devices::push_provider.eq(push.map(|push| push.provider.as_str())),
+                devices::push_token.eq(push.map(|push| push.token.as_str()))
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn clear_push_token_if(&self, device_id: Uuid, token: &str) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::update(devices::table.find(device_id).filter(devices::push_token.eq(token)))
+            .set((
+                devices::push_provider.eq(None::<String>),
+                devices::push_token.eq(None::<String>)
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn push_targets(&self, user_id: Uuid) -> Result<Vec<PushTarget>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(devices::table
+            .filter(devices::user_id.eq(user_id))
+            .filter(devices::push_token.is_not_null())
+            .select((devices::id, devices::push_provider, devices::push_token, devices::missed_messages))
+            .load::<(Uuid, Option<String>, Option<String>, i32)>(&conn)?
+            .into_iter()
+            .filter_map(|(device_id, provider, token, missed_messages)| Some(PushTarget {
+                device_id,
+                push: PushRegistration { provider: provider?, token: token? },
+                missed_messages,
+            }))
+            .collect())
+    }
+
+    fn create_user(&self, user: &UserCreation, device_id: Uuid) -> Result<Uuid, HandlerError> {
+        let conn = self.conn()?;
+        // Assumes the device does not already have a user.
+        conn.transaction::<Uuid, _, _>(|| {
+            let user_id = diesel::insert_into(users::table)
+                .values((
+                    users::email.eq(&user.email),
+                    users::identity_key.eq(&user.identity_key),
+                    users::identity_key_algorithm.eq(user.identity_key_algorithm),
+                    users::signed_prekey.eq(&user.signed_prekey),
+                    users::prekey_signature.eq(&user.prekey_signature),
+                    users::nickname.eq(&user.nickname),
+                    users::bio.eq(&user.bio)
+                ))
+                .returning(users::id)
+                .get_result::<Uuid>(&conn)?;
+
+            // Now update the device - assuming it exists
+            diesel::update(devices::table.find(&device_id))
+                .set(devices::user_id.eq(&user_id))
+                .execute(&conn)?;
+            Ok(user_id)
+        }).map_err(|e| match e {
+            diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, err_dat) =>
+                HandlerError::RecordMustBeUnique { name: err_dat.column_name().unwrap_or("").to_string() },
+            _ => InternalError::DatabaseError(e).into()
+        })
+    }
+
+    fn user_keys(&self, user_id: Uuid) -> Result<UserKeys, HandlerError> {
+        let conn = self.conn()?;
+        let (identity_key, identity_key_algorithm, signed_prekey, prekey_signature) = users::table.find(user_id)
+            .select((users::identity_key, users::identity_key_algorithm, users::signed_prekey, users::prekey_signature))
+            .first::<(Vec<u8>, KeyAlgorithm, Vec<u8>, Vec<u8>)>(&conn)
+            .map_err(not_found(Entity::User { uuid: user_id }))?;
+        Ok(UserKeys { identity_key, identity_key_algorithm, signed_prekey, prekey_signature })
+    }
+
+    fn update_prekey(&self, user_id: Uuid, signed_prekey: &[u8], prekey_signature: &[u8]) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        match diesel::update(users::table.find(user_id))
+            .set((users::signed_prekey.eq(signed_prekey),
+                  users::prekey_signature.eq(prekey_signature)))
+            .execute(&conn)?
+        {
+            0 => Err(unknown_user(user_id)),
+            _ => Ok(())
+        }
+    }
+
+    fn reset_identity(&self, user_id: Uuid, keys: &UserKeys, notices: &dyn Fn(&[Uuid]) -> Vec<NewMessage>) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        conn.transaction::<_, HandlerError, _>(|| {
+            let updated = diesel::update(users::table.find(user_id))
+                .set((users::identity_key.eq(&keys.identity_key),
+                      users::identity_key_algorithm.eq(keys.identity_key_algorithm),
+                      users::signed_prekey.eq(&keys.signed_prekey),
+                      users::prekey_signature.eq(&keys.prekey_signature)))
+                .execute(&conn)?;
+            if updated == 0 {
+                return Err(unknown_user(user_id));
+            }
+
+            diesel::delete(onetimekeys::table.filter(onetimekeys::user_id.eq(user_id)))
+                .execute(&conn)?;
+
+            let mut correspondents = messages::table
+                .filter(messages::recipient.eq(user_id))
+                .select(messages::sender)
+                .distinct()
+                .load::<Uuid>(&conn)?;
+            correspondents.extend(messages::table
+                .filter(messages::sender.eq(user_id))
+                .select(messages::recipient)
+                .distinct()
+                .load::<Uuid>(&conn)?);
+            correspondents.sort();
+            correspondents.dedup();
+            correspondents.retain(|c| *c != user_id);
+            insert_messages(&conn, &notices(&correspondents))?;
+            Ok(correspondents)
+        })
+    }
+
+    fn read_receipts(&self, user_id: Uuid) -> Result<bool, HandlerError> {
+        let conn = self.conn()?;
+        users::table.find(user_id).select(users::read_receipts)
+            .first::<bool>(&conn)
+            .map_err(not_found(Entity::User { uuid: user_id }))
+    }
+
+    fn set_read_receipts(&self, user_id: Uuid, read_receipts: bool) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        match diesel::update(users::table.find(user_id))
+            .set(users::read_receipts.eq(read_receipts))
+            .execute(&conn)?
+        {
+            0 => Err(unknown_user(user_id)),
+            _ => Ok(())
+        }
+    }
+
+    fn add_onetime_keys(&self, user_id: Uuid, keys: &[Vec<u8>]) -> Result<usize, HandlerError> {
+        let conn = self.conn()?;
+        let values = keys.iter()
+            .map(|key| (onetimekeys::prekey.eq(key), onetimekeys::user_id.eq(user_id)))
+            .collect::<Vec<_>>();
+        Ok(diesel::insert_into(onetimekeys::table)
+            .values(&values)
+            .execute(&conn)?)
+    }
+
+    fn take_onetime_key(&self, user_id: Uuid) -> Result<Option<Vec<u8>>, HandlerError> {
+        let conn = self.conn()?;
+        let mut keys = diesel::delete(onetimekeys::table.filter(
+            onetimekeys::id.eq(any(onetimekeys::table.select(onetimekeys::id)
+                .filter(onetimekeys::user_id.eq(user_id))
+                .limit(1).into_boxed()))))
+            .returning(onetimekeys::prekey)
+            .load::<Vec<u8>>(&conn)?;
+        Ok(keys.pop())
+    }
+
+    fn insert_messages(&self, msgs: &[NewMessage]) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(conn.transaction(|| insert_messages(&conn, msgs))?)
+    }
+
+    fn take_mailbox(&self, device_id: Uuid, receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<Vec<MailboxReturn>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(conn.transaction::<Vec<MailboxReturn>, _, _>(|| {
+            let message_ids = diesel::delete(mailbox::table.filter(
+                mailbox::device_id.eq(device_id)))
+                .returning(mailbox::message_id)
+                .load::<Uuid>(&conn)?;
+            diesel::update(devices::table.find(device_id))
+                .set(devices::missed_messages.eq(0))
+                .execute(&conn)?;
+
+            // The first device to collect a message acknowledges its delivery
+            let delivered = diesel::update(messages::table
+                .filter(messages::id.eq_any(&message_ids))
+                .filter(messages::delivered_at.is_null()))
+                .set(messages::delivered_at.eq(Utc::now()))
+                .returning((messages::id, messages::message_type, messages::sender, messages::recipient))
+                .load::<(Uuid, String, Uuid, Uuid)>(&conn)?
+                .into_iter()
+                .map(|(message_id, message_type, sender, recipient)| Delivered { message_id, message_type, sender, recipient })
+                .collect::<Vec<_>>();
+            insert_messages(&conn, &receipts(&delivered))?;
+
+            messages::table.filter(messages::id.eq_any(&message_ids))
+                .select((messages::id, messages::sender, messages::message_type, messages::reception_time, messages::payload, messages::ciphertext))
+                .order(messages::reception_time.asc())
+                .load::<(Uuid, Uuid, String, DateTime<Utc>, Option<serde_json::Value>, Option<Vec<u8>>)>(&conn)
+                .map(|messages| messages.into_iter()
+                    .map(|(id, sender, message_type, timestamp, payload, ciphertext)| MailboxReturn { id, sender, message_type, timestamp, payload, ciphertext })
+                    .collect())
+        })?)
+    }
+
+    fn mark_read(&self, recipient: Uuid, message_ids: &[Uuid], receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<usize, HandlerError> {
+        let conn = self.conn()?;
+        Ok(conn.transaction::<usize, diesel::result::Error, _>(|| {
+            let read = diesel::update(messages::table
+                .filter(messages::id.eq_any(message_ids))
+                .filter(messages::recipient.eq(recipient))
+                .filter(messages::read_at.is_null()))
+                .set(messages::read_at.eq(Utc::now()))
+                .returning((messages::id, messages::message_type, messages::sender))
+                .load::<(Uuid, String, Uuid)>(&conn)?
+                .into_iter()
+                .map(|(message_id, message_type, sender)| Delivered { message_id, message_type, sender, recipient })
+                .collect::<Vec<_>>();
+            Ok(insert_messages(&conn, &receipts(&read))?.len())
+        })?)
+    }
+
+    fn mailbox_backlog(&self) -> Result<i64, HandlerError> {
+        let conn = self.conn()?;
+        Ok(mailbox::table.count().get_result::<i64>(&conn)?)
+    }
+
+    fn insert_attachment(&self, attachment: &NewAttachment) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        insert_attachment(&conn, attachment)?;
+        Ok(())
+    }
+
+    fn attachment_readable(&self, attachment_id: Uuid, user_id: Uuid) -> Result<bool, HandlerError> {
+        let conn = self.conn()?;
+        let uploader = attachments::table.find(attachment_id)
+            .filter(attachments::expires.gt(Utc::now()))
+            .select(attachments::uploader)
+            .first::<Uuid>(&conn)
+            .optional()?;
+        match uploader {
+            None => Ok(false),
+            Some(uploader) if uploader == user_id => Ok(true),
+            Some(_) => Ok(message_attachments::table.inner_join(messages::table)
+                .filter(message_attachments::attachment_id.eq(attachment_id))
+                .filter(messages::recipient.eq(user_id))
+                .select(message_attachments::message_id)
+                .first::<Uuid>(&conn)
+                .optional()?
+                .is_some()),
+        }
+    }
+
+    fn owned_attachments(&self, uploader: Uuid, attachment_ids: &[Uuid]) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(attachments::table
+            .filter(attachments::id.eq_any(attachment_ids))
+            .filter(attachments::uploader.eq(uploader))
+            .filter(attachments::expires.gt(Utc::now()))
+            .select(attachments::id)
+            .load::<Uuid>(&conn)?)
+    }
+
+    fn delete_attachment(&self, attachment_id: Uuid) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::delete(attachments::table.find(attachment_id)).execute(&conn)?;
+        Ok(())
+    }
+
+    fn expire_attachments(&self) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(diesel::delete(attachments::table.filter(attachments::expires.le(Utc::now())))
+            .returning(attachments::id)
+            .load::<Uuid>(&conn)?)
+    }
+
+    fn create_upload(&self, uploader: Uuid, size: i64) -> Result<Uuid, HandlerError> {
+        let conn = self.conn()?;
+        Ok(diesel::insert_into(uploads::table)
+            .values((
+                uploads::uploader.eq(uploader),
+                uploads::size.eq(size)
+            ))
+            .returning(uploads::id)
+            .get_result::<Uuid>(&conn)?)
+    }
+
+    fn upload_state(&self, upload_id: Uuid, uploader: Uuid) -> Result<UploadState, HandlerError> {
+        let conn = self.conn()?;
+        lock_upload(&conn, upload_id, uploader)
+    }
+
+    fn advance_upload(&self, upload_id: Uuid, uploader: Uuid, write: &mut dyn FnMut(UploadState) -> Result<i64, HandlerError>) -> Result<UploadState, HandlerError> {
+        let conn = self.conn()?;
+        conn.transaction::<_, HandlerError, _>(|| {
+            let state = lock_upload(&conn, upload_id, uploader)?;
+            let received = write(state)?;
+            diesel::update(uploads::table.find(upload_id))
+                .set((
+                    uploads::received.eq(received),
+                    uploads::updated.eq(Utc::now())
+                ))
+                .execute(&conn)?;
+            Ok(UploadState { size: state.size, received })
+        })
+    }
+
+    fn finish_upload(&self, upload_id: Uuid, uploader: Uuid, attachment: &NewAttachment, finish: &mut dyn FnMut(UploadState) -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        conn.transaction::<_, HandlerError, _>(|| {
+            finish(lock_upload(&conn, upload_id, uploader)?)?;
+            insert_attachment(&conn, attachment)?;
+            diesel::delete(uploads::table.find(upload_id)).execute(&conn)?;
+            Ok(())
+        })
+    }
+
+    fn expire_uploads(&self, cutoff: DateTime<Utc>) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(diesel::delete(uploads::table.filter(uploads::updated.le(cutoff)))
+            .returning(uploads::id)
+            .load::<Uuid>(&conn)?)
+    }
+
+    fn schema_version(&self) -> Result<SchemaVersion, HandlerError> {
+        // Readiness probes shouldn't wait out the pool's full checkout timeout
+        let conn = self.pool.get_timeout(Duration::from_secs(2))
+            .map_err(InternalError::PoolError)?;
+        Ok(SchemaVersion {
+            expected: migrate::POSTGRES.expected_version(),
+            found: conn.latest_run_migration_version()?
+        })
+    }
+
+    fn connection_stats(&self) -> Option<ConnectionStats> {
+        let state = self.pool.state();
+        Some(ConnectionStats {
+            connections: state.connections,
+            idle: state.idle_connections,
+            max: self.pool.max_size(),
+        })
+    }
+}
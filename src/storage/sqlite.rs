@@ -0,0 +1,618 @@
+use std::io::Write;
+use std::time::Duration;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::backend::Backend;
+use diesel::connection::SimpleConnection;
+use diesel::deserialize::{self, FromSql};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Binary;
+use diesel::sqlite::SqliteConnection;
+use diesel_migrations::MigrationConnection;
+use r2d2::PooledConnection;
+use uuid::Uuid;
+use crate::crypto::KeyAlgorithm;
+use crate::message::{MailboxReturn, NewMessage};
+use crate::migrate;
+use beacon_protocol::v1::session::PushRegistration;
+use beacon_protocol::v1::user::UserCreation;
+use crate::utils::{HandlerError, InternalError, Entity};
+use super::sqlite_schema::{attachments, devices, mailbox, message_attachments, messages, onetimekeys, sessions, uploads, users};
+use super::{Storage, NonceRotation, DeviceKey, PushTarget, UserKeys, Delivered, NewAttachment, UploadState, SchemaVersion, ConnectionStats};
+
+type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
+type Conn = PooledConnection<ConnectionManager<SqliteConnection>>;
+
+// SQLite has no uuid type, so ids are stored as their 16 bytes
+#[derive(Clone, Copy, PartialEq, Debug, AsExpression, FromSqlRow)]
+#[sql_type = "Binary"]
+struct SqlUuid(Uuid);
+
+impl<DB: Backend> ToSql<Binary, DB> for SqlUuid where [u8]: ToSql<Binary, DB> {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+        ToSql::<Binary, DB>::to_sql(&self.0.as_bytes()[..], out)
+    }
+}
+
+impl<DB: Backend> FromSql<Binary, DB> for SqlUuid where Vec<u8>: FromSql<Binary, DB> {
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        let bytes = Vec::<u8>::from_sql(bytes)?;
+        Ok(SqlUuid(Uuid::from_slice(&bytes)?))
+    }
+}
+
+fn sql_uuids(ids: &[Uuid]) -> Vec<SqlUuid> {
+    ids.iter().map(|id| SqlUuid(*id)).collect()
+}
+
+fn utc(time: NaiveDateTime) -> DateTime<Utc> {
+    DateTime::from_utc(time, Utc)
+}
+
+// Applied to every pooled connection. Foreign keys are off by default in SQLite, and
+// without a busy timeout concurrent writers fail immediately instead of waiting.
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000; PRAGMA journal_mode = WAL;")
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+// For single node deployments. Writes which read first take the database's write
+// lock up front (an immediate transaction), which stands in for Postgres' row locks.
+pub struct SqliteStorage {
+    pool: Pool
+}
+
+impl SqliteStorage {
+    // Opens, or creates, the database file at `path`, bringing its schema up to date
+    pub fn open(path: &str) -> Result<Self, String> {
+        let pool = r2d2::Pool::builder()
+            .connection_customizer(Box::new(ConnectionOptions))
+            .build(ConnectionManager::<SqliteConnection>::new(path))
+            .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        let conn = pool.get().map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        migrate::SQLITE.check_schema(&conn)?;
+        Ok(SqliteStorage { pool })
+    }
+
+    fn conn(&self) -> Result<Conn, HandlerError> {
+        self.pool.get().map_err(|e| InternalError::PoolError(e).into())
+    }
+}
+
+fn unknown_user(user_id: Uuid) -> HandlerError {
+    HandlerError::UnknownEntity { entity: Entity::User { uuid: user_id } }
+}
+
+// Maps NotFound onto the entity which was being looked up
+fn not_found(entity: Entity) -> impl FnOnce(diesel::result::Error) -> HandlerError {
+    move |e| match e {
+        diesel::result::Error::NotFound => HandlerError::UnknownEntity { entity },
+        _ => InternalError::DatabaseError(e).into()
+    }
+}
+
+fn rotate_nonce(conn: &SqliteConnection, rotation: &NonceRotation) -> Result<(), HandlerError> {
+    let (session_id, expires) = sessions::table.filter(sessions::nonce.eq(&rotation.nonce))
+        .select((sessions::id, sessions::expires))
+        .first::<(i32, NaiveDateTime)>(conn)
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => HandlerError::SessionInvalid,
+            _ => InternalError::DatabaseError(e).into()
+        })?;
+
+    if expires <= Utc::now().naive_utc() {
+        diesel::delete(sessions::table.find(session_id)).execute(conn)?;
+        return Err(HandlerError::SessionInvalid);
+    }
+    diesel::update(sessions::table.find(session_id))
+        .set((
+            sessions::nonce.eq(&rotation.new_nonce),
+            sessions::expires.eq(rotation.expires)
+        )).execute(conn)?;
+    Ok(())
+}
+
+fn insert_message(conn: &SqliteConnection, msg: &NewMessage) -> QueryResult<Uuid> {
+    let device_ids = devices::table.filter(devices::user_id.eq(SqlUuid(msg.recipient)))
+        .select(devices::id).load::<SqlUuid>(conn)?;
+    if msg.counts_as_missed() {
+        diesel::update(devices::table.filter(devices::user_id.eq(SqlUuid(msg.recipient))))
+            .set(devices::missed_messages.eq(devices::missed_messages + 1))
+            .execute(conn)?;
+    }
+
+    let message_id = Uuid::new_v4();
+    diesel::insert_into(messages::table)
+        .values((
+            messages::id.eq(SqlUuid(message_id)),
+            messages::recipient.eq(SqlUuid(msg.recipient)),
+            messages::sender.eq(SqlUuid(msg.sender)),
+            messages::reception_time.eq(Utc::now().naive_utc()),
+            messages::message_type.eq(&msg.message_type),
+            messages::payload.eq(msg.payload.as_ref().map(|payload| payload.to_string())),
+            messages::ciphertext.eq(&msg.ciphertext)
+        ))
+        .execute(conn)?;
+
+    // Diesel can't insert several rows at once into SQLite, so these go one at a time
+    for device_id in &device_ids {
+        diesel::insert_into(mailbox::table)
+            .values((mailbox::device_id.eq(device_id), mailbox::message_id.eq(SqlUuid(message_id))))
+            .execute(conn)?;
+    }
+
+    for attachment_id in &msg.attachments {
+        diesel::insert_into(message_attachments::table)
+            .values((message_attachments::message_id.eq(SqlUuid(message_id)),
+                     message_attachments::attachment_id.eq(SqlUuid(*attachment_id))))
+            .execute(conn)?;
+    }
+    Ok(message_id)
+}
+
+fn insert_messages(conn: &SqliteConnection, msgs: &[NewMessage]) -> QueryResult<Vec<Uuid>> {
+    msgs.iter().map(|msg| insert_message(conn, msg)).collect()
+}
+
+fn insert_attachment(conn: &SqliteConnection, attachment: &NewAttachment) -> QueryResult<usize> {
+    diesel::insert_into(attachments::table)
+        .values((
+            attachments::id.eq(SqlUuid(attachment.id)),
+            attachments::uploader.eq(SqlUuid(attachment.uploader)),
+            attachments::size.eq(attachment.size),
+            attachments::created.eq(Utc::now().naive_utc()),
+            attachments::expires.eq(attachment.expires.naive_utc())
+        ))
+        .execute(conn)
+}
+
+fn upload_state(conn: &SqliteConnection, upload_id: Uuid, uploader: Uuid) -> Result<UploadState, HandlerError> {
+    let (size, received) = uploads::table.find(SqlUuid(upload_id))
+        .filter(uploads::uploader.eq(SqlUuid(uploader)))
+        .select((uploads::size, uploads::received))
+        .first::<(i64, i64)>(conn)
+        .map_err(not_found(Entity::Upload { uuid: upload_id }))?;
+    Ok(UploadState { size, received })
+}
+
+fn from_sql_uuids(ids: Vec<SqlUuid>) -> Vec<Uuid> {
+    ids.into_iter().map(|SqlUuid(id)| id).collect()
+}
+
+impl Storage for SqliteStorage {
+    fn create_session(&self, nonce: &[u8], expires: NaiveDateTime) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::insert_into(sessions::table)
+            .values((
+                sessions::nonce.eq(nonce),
+                sessions::expires.eq(expires)
+            )).execute(&conn)?;
+        Ok(())
+    }
+
+    fn rotate_nonce(&self, rotation: &NonceRotation) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        rotate_nonce(&conn, rotation)
+    }
+
+    fn create_device(&self, public_key: &[u8], algorithm: KeyAlgorithm, rotation: &NonceRotation) -> Result<Uuid, HandlerError> {
+        let conn = self.conn()?;
+        conn.immediate_transaction::<_, HandlerError, _>(|| {
+            rotate_nonce(&conn, rotation)?;
+            let device_id = Uuid::new_v4();
+            diesel::insert_into(devices::table)
+                .values((
+                    devices::id.eq(SqlUuid(device_id)),
+                    devices::public_key.eq(public_key),
+                    devices::key_algorithm.eq(algorithm)
+                ))
+                .execute(&conn)?;
+            Ok(device_id)
+        })
+    }
+
+    fn device_key(&self, device_id: Uuid) -> Result<DeviceKey, HandlerError> {
+        let conn = self.conn()?;
+        let (public_key, algorithm, user_id) = devices::table.find(SqlUuid(device_id))
+            .select((devices::public_key, devices::key_algorithm, devices::user_id))
+            .first::<(Vec<u8>, KeyAlgorithm, Option<SqlUuid>)>(&conn)
+            .map_err(not_found(Entity::Device { uuid: device_id }))?;
+        Ok(DeviceKey { public_key, algorithm, user_id: user_id.map(|SqlUuid(id)| id) })
+    }
+
+    fn set_push_token(&self, device_id: Uuid, push: Option<&PushRegistration>) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::update(devices::table.find(SqlUuid(device_id)))
+            .set((
+                devices::push_provider.eq(push.map(|push| push.provider.as_str())),
+                devices::push_token.eq(push.map(|push| push.token.as_str()))
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn clear_push_token_if(&self, device_id: Uuid, token: &str) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::update(devices::table.find(SqlUuid(device_id)).filter(devices::push_token.eq(token)))
+            .set((
+                devices::push_provider.eq(None::<String>),
+                devices::push_token.eq(None::<String>)
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn push_targets(&self, user_id: Uuid) -> Result<Vec<PushTarget>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(devices::table
+            .filter(devices::user_id.eq(SqlUuid(user_id)))
+            .filter(devices::push_token.is_not_null())
+            .select((devices::id, devices::push_provider, devices::push_token, devices::missed_messages))
+            .load::<(SqlUuid, Option<String>, Option<String>, i32)>(&conn)?
+            .into_iter()
+            .filter_map(|(SqlUuid(device_id), provider, token, missed_messages)| Some(PushTarget {
+                device_id,
+                push: PushRegistration { provider: provider?, token: token? },
+                missed_messages,
+            }))
+            .collect())
+    }
+
+    fn create_user(&self, user: &UserCreation, device_id: Uuid) -> Result<Uuid, HandlerError> {
+        let conn = self.conn()?;
+        let user_id = Uuid::new_v4();
+        // Assumes the device does not already have a user.
+        conn.immediate_transaction(|| {
+            diesel::insert_into(users::table)
+                .values((
+                    users::id.eq(SqlUuid(user_id)),
+                    users::email.eq(&user.email),
+                    users::identity_key.eq(&user.identity_key),
+                    users::identity_key_algorithm.eq(user.identity_key_algorithm),
+                    users::signed_prekey.eq(&user.signed_prekey),
+                    users::prekey_signature.eq(&user.prekey_signature),
+                    users::nickname.eq(&user.nickname),
+                    users::bio.eq(&user.bio)
+                ))
+                .execute(&conn)?;
+
+            // Now update the device - assuming it exists
+            diesel::update(devices::table.find(SqlUuid(device_id)))
+                .set(devices::user_id.eq(SqlUuid(user_id)))
+                .execute(&conn)?;
+            Ok(user_id)
+        }).map_err(|e| match e {
+            // SQLite doesn't report the column, only a message ending in table.column
+            diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, err_dat) =>
+                HandlerError::RecordMustBeUnique { name: err_dat.message().rsplit('.').next().unwrap_or("").to_string() },
+            _ => InternalError::DatabaseError(e).into()
+        })
+    }
+
+    fn user_keys(&self, user_id: Uuid) -> Result<UserKeys, HandlerError> {
+        let conn = self.conn()?;
+        let (identity_key, identity_key_algorithm, signed_prekey, prekey_signature) = users::table.find(SqlUuid(user_id))
+            .select((users::identity_key, users::identity_key_algorithm, users::signed_prekey, users::prekey_signature))
+            .first::<(Vec<u8>, KeyAlgorithm, Vec<u8>, Vec<u8>)>(&conn)
+            .map_err(not_found(Entity::User { uuid: user_id }))?;
+        Ok(UserKeys { identity_key, identity_key_algorithm, signed_prekey, prekey_signature })
+    }
+
+    fn update_prekey(&self, user_id: Uuid, signed_prekey: &[u8], prekey_signature: &[u8]) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        match diesel::update(users::table.find(SqlUuid(user_id)))
+            .set((users::signed_prekey.eq(signed_prekey),
+                  users::prekey_signature.eq(prekey_signature)))
+            .execute(&conn)?
+        {
+            0 => Err(unknown_user(user_id)),
+            _ => Ok(())
+        }
+    }
+
+    fn reset_identity(&self, user_id: Uuid, keys: &UserKeys, notices: &dyn Fn(&[Uuid]) -> Vec<NewMessage>) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        conn.immediate_transaction::<_, HandlerError, _>(|| {
+            let updated = diesel::update(users::table.find(SqlUuid(user_id)))
+                .set((users::identity_key.eq(&keys.identity_key),
+                      users::identity_key_algorithm.eq(keys.identity_key_algorithm),
+                      users::signed_prekey.eq(&keys.signed_prekey),
+                      users::prekey_signature.eq(&keys.prekey_signature)))
+                .execute(&conn)?;
+            if updated == 0 {
+                return Err(unknown_user(user_id));
+            }
+
+            diesel::delete(onetimekeys::table.filter(onetimekeys::user_id.eq(SqlUuid(user_id))))
+                .execute(&conn)?;
+
+            let mut correspondents = messages::table
+                .filter(messages::recipient.eq(SqlUuid(user_id)))
+                .select(messages::sender)
+                .distinct()
+                .load::<SqlUuid>(&conn)?;
+            correspondents.extend(messages::table
+                .filter(messages::sender.eq(SqlUuid(user_id)))
+                .select(messages::recipient)
+                .distinct()
+                .load::<SqlUuid>(&conn)?);
+            let mut correspondents = from_sql_uuids(correspondents);
+            correspondents.sort();
+            correspondents.dedup();
+            correspondents.retain(|c| *c != user_id);
+            insert_messages(&conn, &notices(&correspondents))?;
+            Ok(correspondents)
+        })
+    }
+
+    fn read_receipts(&self, user_id: Uuid) -> Result<bool, HandlerError> {
+        let conn = self.conn()?;
+        users::table.find(SqlUuid(user_id)).select(users::read_receipts)
+            .first::<bool>(&conn)
+            .map_err(not_found(Entity::User { uuid: user_id }))
+    }
+
+    fn set_read_receipts(&self, user_id: Uuid, read_receipts: bool) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        match diesel::update(users::table.find(SqlUuid(user_id)))
+            .set(users::read_receipts.eq(read_receipts))
+            .execute(&conn)?
+        {
+            0 => Err(unknown_user(user_id)),
+            _ => Ok(())
+        }
+    }
+
+    fn add_onetime_keys(&self, user_id: Uuid, keys: &[Vec<u8>]) -> Result<usize, HandlerError> {
+        let conn = self.conn()?;
+        conn.immediate_transaction::<_, HandlerError, _>(|| {
+            for key in keys {
+                diesel::insert_into(onetimekeys::table)
+                    .values((onetimekeys::prekey.eq(key), onetimekeys::user_id.eq(SqlUuid(user_id))))
+                    .execute(&conn)?;
+            }
+            Ok(keys.len())
+        })
+    }
+
+    fn take_onetime_key(&self, user_id: Uuid) -> Result<Option<Vec<u8>>, HandlerError> {
+        let conn = self.conn()?;
+        conn.immediate_transaction(|| {
+            let key = onetimekeys::table
+                .filter(onetimekeys::user_id.eq(SqlUuid(user_id)))
+                .select((onetimekeys::id, onetimekeys::prekey))
+                .first::<(i32, Vec<u8>)>(&conn)
+                .optional()?;
+            match key {
+                Some((key_id, prekey)) => {
+                    diesel::delete(onetimekeys::table.find(key_id)).execute(&conn)?;
+                    Ok(Some(prekey))
+                },
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn insert_messages(&self, msgs: &[NewMessage]) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(conn.immediate_transaction(|| insert_messages(&conn, msgs))?)
+    }
+
+    fn take_mailbox(&self, device_id: Uuid, receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<Vec<MailboxReturn>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(conn.immediate_transaction::<Vec<MailboxReturn>, diesel::result::Error, _>(|| {
+            let message_ids = mailbox::table.filter(mailbox::device_id.eq(SqlUuid(device_id)))
+                .select(mailbox::message_id)
+                .load::<SqlUuid>(&conn)?;
+            diesel::delete(mailbox::table.filter(mailbox::device_id.eq(SqlUuid(device_id))))
+                .execute(&conn)?;
+            diesel::update(devices::table.find(SqlUuid(device_id)))
+                .set(devices::missed_messages.eq(0))
+                .execute(&conn)?;
+
+            // The first device to collect a message acknowledges its delivery
+            let delivered = messages::table
+                .filter(messages::id.eq_any(&message_ids))
+                .filter(messages::delivered_at.is_null())
+                .select((messages::id, messages::message_type, messages::sender, messages::recipient))
+                .load::<(SqlUuid, String, SqlUuid, SqlUuid)>(&conn)?
+                .into_iter()
+                .map(|(SqlUuid(message_id), message_type, SqlUuid(sender), SqlUuid(recipient))| Delivered { message_id, message_type, sender, recipient })
+                .collect::<Vec<_>>();
+            diesel::update(messages::table
+                .filter(messages::id.eq_any(&message_ids))
+                .filter(messages::delivered_at.is_null()))
+                .set(messages::delivered_at.eq(Utc::now().naive_utc()))
+                .execute(&conn)?;
+            insert_messages(&conn, &receipts(&delivered))?;
+
+            messages::table.filter(messages::id.eq_any(&message_ids))
+                .select((messages::id, messages::sender, messages::message_type, messages::reception_time, messages::payload, messages::ciphertext))
+                .order(messages::reception_time.asc())
+                .load::<(SqlUuid, SqlUuid, String, NaiveDateTime, Option<String>, Option<Vec<u8>>)>(&conn)?
+                .into_iter()
+                .map(|(SqlUuid(id), SqlUuid(sender), message_type, timestamp, payload, ciphertext)| Ok(MailboxReturn {
+                    id,
+                    sender,
+                    message_type,
+                    timestamp: utc(timestamp),
+                    payload: payload.map(|payload| serde_json::from_str(&payload)).transpose()
+                        .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?,
+                    ciphertext,
+                }))
+                .collect()
+        })?)
+    }
+
+    fn mark_read(&self, recipient: Uuid, message_ids: &[Uuid], receipts: &dyn Fn(&[Delivered]) -> Vec<NewMessage>) -> Result<usize, HandlerError> {
+        let conn = self.conn()?;
+        Ok(conn.immediate_transaction::<usize, diesel::result::Error, _>(|| {
+            let message_ids = sql_uuids(message_ids);
+            let read = messages::table
+                .filter(messages::id.eq_any(&message_ids))
+                .filter(messages::recipient.eq(SqlUuid(recipient)))
+                .filter(messages::read_at.is_null())
+                .select((messages::id, messages::message_type, messages::sender))
+                .load::<(SqlUuid, String, SqlUuid)>(&conn)?
+                .into_iter()
+                .map(|(SqlUuid(message_id), message_type, SqlUuid(sender))| Delivered { message_id, message_type, sender, recipient })
+                .collect::<Vec<_>>();
+            diesel::update(messages::table
+                .filter(messages::id.eq_any(&message_ids))
+                .filter(messages::recipient.eq(SqlUuid(recipient)))
+                .filter(messages::read_at.is_null()))
+                .set(messages::read_at.eq(Utc::now().naive_utc()))
+                .execute(&conn)?;
+            Ok(insert_messages(&conn, &receipts(&read))?.len())
+        })?)
+    }
+
+    fn mailbox_backlog(&self) -> Result<i64, HandlerError> {
+        let conn = self.conn()?;
+        Ok(mailbox::table.count().get_result::<i64>(&conn)?)
+    }
+
+    fn insert_attachment(&self, attachment: &NewAttachment) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        insert_attachment(&conn, attachment)?;
+        Ok(())
+    }
+
+    fn attachment_readable(&self, attachment_id: Uuid, user_id: Uuid) -> Result<bool, HandlerError> {
+        let conn = self.conn()?;
+        let uploader = attachments::table.find(SqlUuid(attachment_id))
+            .filter(attachments::expires.gt(Utc::now().naive_utc()))
+            .select(attachments::uploader)
+            .first::<SqlUuid>(&conn)
+            .optional()?;
+        match uploader {
+            None => Ok(false),
+            Some(SqlUuid(uploader)) if uploader == user_id => Ok(true),
+            Some(_) => {
+                let message_ids = message_attachments::table
+                    .filter(message_attachments::attachment_id.eq(SqlUuid(attachment_id)))
+                    .select(message_attachments::message_id)
+                    .load::<SqlUuid>(&conn)?;
+                Ok(messages::table
+                    .filter(messages::id.eq_any(&message_ids))
+                    .filter(messages::recipient.eq(SqlUuid(user_id)))
+                    .select(messages::id)
+                    .first::<SqlUuid>(&conn)
+                    .optional()?
+                    .is_some())
+            },
+        }
+    }
+
+    fn owned_attachments(&self, uploader: Uuid, attachment_ids: &[Uuid]) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(attachments::table
+            .filter(attachments::id.eq_any(sql_uuids(attachment_ids)))
+            .filter(attachments::uploader.eq(SqlUuid(uploader)))
+            .filter(attachments::expires.gt(Utc::now().naive_utc()))
+            .select(attachments::id)
+            .load::<SqlUuid>(&conn)?
+            .into_iter()
+            .map(|SqlUuid(id)| id)
+            .collect())
+    }
+
+    fn delete_attachment(&self, attachment_id: Uuid) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        diesel::delete(attachments::table.find(SqlUuid(attachment_id))).execute(&conn)?;
+        Ok(())
+    }
+
+    fn expire_attachments(&self) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        let now = Utc::now().naive_utc();
+        Ok(conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let expired = attachments::table.filter(attachments::expires.le(now))
+                .select(attachments::id)
+                .load::<SqlUuid>(&conn)?;
+            diesel::delete(attachments::table.filter(attachments::id.eq_any(&expired)))
+                .execute(&conn)?;
+            Ok(from_sql_uuids(expired))
+        })?)
+    }
+
+    fn create_upload(&self, uploader: Uuid, size: i64) -> Result<Uuid, HandlerError> {
+        let conn = self.conn()?;
+        let upload_id = Uuid::new_v4();
+        diesel::insert_into(uploads::table)
+            .values((
+                uploads::id.eq(SqlUuid(upload_id)),
+                uploads::uploader.eq(SqlUuid(uploader)),
+                uploads::size.eq(size),
+                uploads::updated.eq(Utc::now().naive_utc())
+            ))
+            .execute(&conn)?;
+        Ok(upload_id)
+    }
+
+    fn upload_state(&self, upload_id: Uuid, uploader: Uuid) -> Result<UploadState, HandlerError> {
+        let conn = self.conn()?;
+        upload_state(&conn, upload_id, uploader)
+    }
+
+    fn advance_upload(&self, upload_id: Uuid, uploader: Uuid, write: &mut dyn FnMut(UploadState) -> Result<i64, HandlerError>) -> Result<UploadState, HandlerError> {
+        let conn = self.conn()?;
+        conn.immediate_transaction::<_, HandlerError, _>(|| {
+            let state = upload_state(&conn, upload_id, uploader)?;
+            let received = write(state)?;
+            diesel::update(uploads::table.find(SqlUuid(upload_id)))
+                .set((
+                    uploads::received.eq(received),
+                    uploads::updated.eq(Utc::now().naive_utc())
+                ))
+                .execute(&conn)?;
+            Ok(UploadState { size: state.size, received })
+        })
+    }
+
+    fn finish_upload(&self, upload_id: Uuid, uploader: Uuid, attachment: &NewAttachment, finish: &mut dyn FnMut(UploadState) -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        let conn = self.conn()?;
+        conn.immediate_transaction::<_, HandlerError, _>(|| {
+            finish(upload_state(&conn, upload_id, uploader)?)?;
+            insert_attachment(&conn, attachment)?;
+            diesel::delete(uploads::table.find(SqlUuid(upload_id))).execute(&conn)?;
+            Ok(())
+        })
+    }
+
+    fn expire_uploads(&self, cutoff: DateTime<Utc>) -> Result<Vec<Uuid>, HandlerError> {
+        let conn = self.conn()?;
+        Ok(conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let expired = uploads::table.filter(uploads::updated.le(cutoff.naive_utc()))
+                .select(uploads::id)
+                .load::<SqlUuid>(&conn)?;
+            diesel::delete(uploads::table.filter(uploads::id.eq_any(&expired)))
+                .execute(&conn)?;
+            Ok(from_sql_uuids(expired))
+        })?)
+    }
+
+    fn schema_version(&self) -> Result<SchemaVersion, HandlerError> {
+        let conn = self.pool.get_timeout(Duration::from_secs(2))
+            .map_err(InternalError::PoolError)?;
+        Ok(SchemaVersion {
+            expected: migrate::SQLITE.expected_version(),
+            found: conn.latest_run_migration_version()?
+        })
+    }
+
+    fn connection_stats(&self) -> Option<ConnectionStats> {
+        let state = self.pool.state();
+        Some(ConnectionStats {
+            connections: state.connections,
+            idle: state.idle_connections,
+            max: self.pool.max_size(),
+        })
+    }
+}
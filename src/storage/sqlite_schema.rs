@@ -0,0 +1,95 @@
+// schema.rs in the types of migrations_sqlite/, where uuids are blobs, times are
+// naive UTC and JSON is text
+table! {
+    attachments (id) {
+        id -> Binary,
+        uploader -> Binary,
+        size -> BigInt,
+        created -> Timestamp,
+        expires -> Timestamp,
+    }
+}
+
+table! {
+    devices (id) {
+        id -> Binary,
+        user_id -> Nullable<Binary>,
+        missed_messages -> Integer,
+        public_key -> Binary,
+        key_algorithm -> Text,
+        push_provider -> Nullable<Text>,
+        push_token -> Nullable<Text>,
+    }
+}
+
+table! {
+    mailbox (id) {
+        id -> Integer,
+        device_id -> Binary,
+        message_id -> Binary,
+    }
+}
+
+table! {
+    message_attachments (message_id, attachment_id) {
+        message_id -> Binary,
+        attachment_id -> Binary,
+    }
+}
+
+table! {
+    messages (id) {
+        id -> Binary,
+        recipient -> Binary,
+        sender -> Binary,
+        reception_time -> Timestamp,
+        message_type -> Text,
+        payload -> Nullable<Text>,
+        delivered_at -> Nullable<Timestamp>,
+        ciphertext -> Nullable<Binary>,
+        read_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    onetimekeys (id) {
+        id -> Integer,
+        user_id -> Nullable<Binary>,
+        prekey -> Binary,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Integer,
+        nonce -> Binary,
+        expires -> Timestamp,
+    }
+}
+
+table! {
+    uploads (id) {
+        id -> Binary,
+        uploader -> Binary,
+        size -> BigInt,
+        received -> BigInt,
+        updated -> Timestamp,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Binary,
+        identity_key -> Binary,
+        signed_prekey -> Binary,
+        prekey_signature -> Binary,
+        bio -> Nullable<Text>,
+        profile_thumb -> Nullable<Binary>,
+        email -> Text,
+        email_verified -> Bool,
+        nickname -> Nullable<Text>,
+        last_seen -> Nullable<Timestamp>,
+        read_receipts -> Bool,
+        identity_key_algorithm -> Text,
+    }
+}
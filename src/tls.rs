@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, ResolvesServerCert, RootCertStore, ServerConfig, SignatureScheme};
+
+use crate::jobs::Job;
+
+// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+// TLS_CERT and TLS_KEY name PEM files holding the certificate chain and its private
+// key. They are re-read whenever either changes, so renewed certificates are used
+// without a restart. TLS_CLIENT_CA optionally names a PEM bundle of the CAs admin
+// clients' certificates must chain to, so it needs ADMIN_PORT.
+pub struct TlsConfig {
+    certificate: Arc<ReloadingCertificate>,
+    client_ca: Option<RootCertStore>,
+}
+
+impl TlsConfig {
+    // None if TLS is left to a proxy
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let client_ca = dotenv::var("TLS_CLIENT_CA").ok();
+        if client_ca.is_some() && dotenv::var("ADMIN_PORT").is_err() {
+            panic!("TLS_CLIENT_CA only applies to the admin port, so ADMIN_PORT must be set");
+        }
+        let (cert_path, key_path) = match (dotenv::var("TLS_CERT"), dotenv::var("TLS_KEY")) {
+            (Ok(cert), Ok(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+            (Err(_), Err(_)) if client_ca.is_none() => return Ok(None),
+            (Err(_), Err(_)) => panic!("TLS_CLIENT_CA needs TLS_CERT and TLS_KEY to be set"),
+            _ => panic!("TLS_CERT and TLS_KEY must be set together"),
+        };
+        let client_ca = match client_ca {
+            Some(path) => Some(load_roots(Path::new(&path))?),
+            None => None,
+        };
+        let certificate = Arc::new(ReloadingCertificate::load(cert_path, key_path)?);
+        Ok(Some(TlsConfig { certificate, client_ca }))
+    }
+
+    // Clients of the API authenticate with session signatures, not certificates
+    pub fn server_config(&self) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = self.certificate.clone();
+        config
+    }
+
+    // Requires a client certificate signed by TLS_CLIENT_CA, if it is set
+    pub fn admin_config(&self) -> ServerConfig {
+        let verifier = match &self.client_ca {
+            Some(roots) => AllowAnyAuthenticatedClient::new(roots.clone()),
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = self.certificate.clone();
+        config
+    }
+
+    pub fn spawn_reload(&self) -> Job {
+        let certificate = self.certificate.clone();
+        Job::spawn("certificate reload", RELOAD_INTERVAL, move || {
+            certificate.reload_if_changed();
+            futures::future::ready(())
+        })
+    }
+}
+
+// Serves whichever certificate was loaded last, for connections made after a reload
+struct ReloadingCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<CertifiedKey>,
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCertificate {
+    fn load(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let current = RwLock::new(load_certified_key(&cert_path, &key_path)?);
+        let modified = Mutex::new((modified(&cert_path), modified(&key_path)));
+        Ok(ReloadingCertificate { cert_path, key_path, current, modified })
+    }
+
+    fn reload_if_changed(&self) {
+        let modified_now = (modified(&self.cert_path), modified(&self.key_path));
+        let mut last_modified = self.modified.lock().expect("Certificate lock poisoned");
+        if *last_modified == modified_now {
+            return;
+        }
+        // Renewals may replace the two files one at a time, so a failure is
+        // retried at the next check rather than recorded as loaded
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().expect("Certificate lock poisoned") = key;
+                *last_modified = modified_now;
+                info!("Reloaded TLS certificate from {}", self.cert_path.display());
+            },
+            Err(e) => warn!("Keeping the current TLS certificate, reloading failed: {}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _server_name: Option<webpki::DNSNameRef>, _sigschemes: &[SignatureScheme]) -> Option<CertifiedKey> {
+        Some(self.current.read().expect("Certificate lock poisoned").clone())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn invalid(path: &Path, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} does not contain a PEM {}", path.display(), what))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let chain = pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_e| invalid(cert_path, "certificate chain"))?;
+    if chain.is_empty() {
+        return Err(invalid(cert_path, "certificate chain"));
+    }
+    let key = load_key(key_path)?.ok_or_else(|| invalid(key_path, "private key"))?;
+    let key = sign::any_supported_type(&key).map_err(|_e| invalid(key_path, "supported private key"))?;
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+// PKCS#8, or PKCS#1 as older tools write RSA keys
+fn load_key(path: &Path) -> io::Result<Option<PrivateKey>> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?)).unwrap_or_default();
+    if let Some(key) = pkcs8.into_iter().next() {
+        return Ok(Some(key));
+    }
+    let rsa = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?)).unwrap_or_default();
+    Ok(rsa.into_iter().next())
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let (added, _skipped) = roots.add_pem_file(&mut BufReader::new(File::open(path)?))
+        .map_err(|_e| invalid(path, "CA bundle"))?;
+    if added == 0 {
+        return Err(invalid(path, "CA bundle"));
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+    }
+
+    fn served(certificate: &ReloadingCertificate) -> rustls::Certificate {
+        certificate.current.read().unwrap().cert[0].clone()
+    }
+
+    #[test]
+    fn reload_picks_up_rewritten_files() {
+        let dir = std::env::temp_dir().join(format!("beacon-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("tls.crt"), dir.join("tls.key"));
+        std::fs::copy(fixture("first.crt"), &cert_path).unwrap();
+        std::fs::copy(fixture("first.key"), &key_path).unwrap();
+        let first = load_certified_key(&fixture("first.crt"), &fixture("first.key")).unwrap().cert[0].clone();
+        let second = load_certified_key(&fixture("second.crt"), &fixture("second.key")).unwrap().cert[0].clone();
+
+        let certificate = ReloadingCertificate::load(cert_path.clone(), key_path.clone()).unwrap();
+        certificate.reload_if_changed();
+        assert_eq!(served(&certificate), first);
+
+        // Some filesystems only record modification times to the second
+        std::thread::sleep(Duration::from_millis(1100));
+        std::fs::copy(fixture("second.crt"), &cert_path).unwrap();
+        std::fs::copy(fixture("second.key"), &key_path).unwrap();
+        certificate.reload_if_changed();
+        assert_eq!(served(&certificate), second);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,141 +1,83 @@
-use crate::database::{Pool, extract_connection};
 use uuid::Uuid;
-use crate::schema::{users, devices, onetimekeys};
-use diesel::prelude::*;
-use crate::utils::{HandlerError, InternalError, Entity};
-use crate::base64enc;
-use serde::{Deserialize, Serialize};
-use diesel::result::Error;
-use diesel::pg::expression::array_comparison::any;
-use actix_web::http::header::q;
-
-fn check_signed_prekey(identity_key: &Vec<u8>, signed_key: &Vec<u8>, signature: &Vec<u8>) -> Result<(), HandlerError>{
-    let key = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, identity_key);
-    key.verify(signed_key, signature).map_err(|_| HandlerError::SignatureMismatch)
-}
-
-#[derive(Deserialize, Insertable)]
-#[table_name = "users"]
-pub struct UserCreation {
-    email: String,
-    #[serde(with = "base64enc")]
-    identity_key: Vec<u8>,
-    #[serde(with = "base64enc")]
-    signed_prekey: Vec<u8>,
-    #[serde(with = "base64enc")]
-    prekey_signature: Vec<u8>,
-    nickname: Option<String>,
-    bio: Option<String>,
+use beacon_protocol::v1::user::{UserCreation, PreKeyUpdate, ChatPackage, UserSettings, IdentityReset};
+use crate::message;
+use crate::metrics;
+use crate::storage::{Storage, UserKeys};
+use crate::utils::HandlerError;
+use crate::crypto::{self, KeyAlgorithm};
+
+fn check_signed_prekey(algorithm: KeyAlgorithm, identity_key: &[u8], signed_key: &[u8], signature: &[u8]) -> Result<(), HandlerError>{
+    algorithm.verify(identity_key, signed_key, signature).map_err(|_| HandlerError::SignatureMismatch)
 }
 
-pub fn create_user(pool: &Pool, user: UserCreation, device_id: Uuid) -> Result<Uuid, HandlerError> {
-    check_signed_prekey(&user.identity_key, &user.signed_prekey, &user.prekey_signature)?;
+pub fn create_user(store: &dyn Storage, user: UserCreation, device_id: Uuid) -> Result<Uuid, HandlerError> {
+    crypto::check_public_key(user.identity_key_algorithm, &user.identity_key)?;
+    check_signed_prekey(user.identity_key_algorithm, &user.identity_key, &user.signed_prekey, &user.prekey_signature)?;
 
-    let conn = extract_connection(pool)?;
-    // Assumes the device does not already have a user.
-    conn.transaction::<Uuid, _, _>(|| {
-        let user_id = diesel::insert_into(users::table).values(&user)
-            .returning(users::id)
-            .get_result::<Uuid>(&conn)?;
-
-        // Now update the device - assuming it exists
-        diesel::update(devices::table.find(&device_id))
-            .set(devices::user_id.eq(&user_id))
-            .execute(&conn)?;
-        Ok(user_id)
-    }).map_err(|e| match e {
-        diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, err_dat) =>
-            HandlerError::RecordMustBeUnique { name: err_dat.column_name().unwrap_or("").to_string() },
-        _ => InternalError::DatabaseError(e).into()
-    })
+    store.create_user(&user, device_id)
     // TODO: Send verification email
 }
 
-#[derive(Deserialize)]
-pub struct PreKeyUpdate {
-    #[serde(with = "base64enc")]
-    signed_prekey: Vec<u8>,
-    #[serde(with = "base64enc")]
-    prekey_signature: Vec<u8>,
-}
-
-pub fn update_prekey(pool: &Pool, update: PreKeyUpdate, user_id: Uuid) -> Result<(), HandlerError> {
-    let conn = extract_connection(pool)?;
-
-    let identity_key = users::table.find(user_id).select(users::identity_key)
-        .first::<Vec<u8>>(&conn)
-        .map_err(|e| match e {
-            diesel::result::Error::NotFound => HandlerError::UnknownEntity {entity: Entity::User { uuid: user_id}},
-            _ => InternalError::DatabaseError(e).into()
-        })?;
-
-    check_signed_prekey(&identity_key, &update.signed_prekey, &update.prekey_signature)?;
-
-    match diesel::update(users::table.find(user_id))
-        .set((users::signed_prekey.eq(&update.signed_prekey),
-              users::prekey_signature.eq(&update.prekey_signature)))
-        .execute(&conn)
-        .map_err(|e| InternalError::DatabaseError(e))?
-    {
-        0 => Err(HandlerError::UnknownEntity { entity: Entity::User { uuid: user_id } }),
-        _ => Ok(())
-    }
-}
+pub fn update_prekey(store: &dyn Storage, update: PreKeyUpdate, user_id: Uuid) -> Result<(), HandlerError> {
+    let keys = store.user_keys(user_id)?;
+    check_signed_prekey(keys.identity_key_algorithm, &keys.identity_key, &update.signed_prekey, &update.prekey_signature)?;
 
-#[derive(Deserialize)]
-pub struct OTKAdd {
-    pub keys: Vec<String>
+    store.update_prekey(user_id, &update.signed_prekey, &update.prekey_signature)
 }
 
-pub fn add_otks(pool: &Pool, keys: &Vec<String>, user_id: Uuid) -> Result<usize, HandlerError> {
-    if keys.len() == 0 { return Ok(0) };
-    let conn = extract_connection(pool)?;
-
-    let values = keys.iter().map(|s| -> Result<_, HandlerError> {Ok((
-        onetimekeys::prekey.eq(base64::decode(s)
-        .map_err(|_e|HandlerError::MalformedBody { error_message: "base64 error".to_string()})?),
-    onetimekeys::user_id.eq(&user_id)))}).collect::<Result<Vec<_>, HandlerError>>()?;
+pub fn add_otks(store: &dyn Storage, keys: &[String], user_id: Uuid) -> Result<usize, HandlerError> {
+    if keys.is_empty() { return Ok(0) };
 
-    diesel::insert_into(onetimekeys::table)
-        .values(&values)
-        .execute(&conn).map_err(|e| InternalError::DatabaseError(e).into())
-}
+    let keys = keys.iter().map(|s| base64::decode(s)
+        .map_err(|_e| HandlerError::MalformedBody { error_message: "base64 error".to_string()}))
+        .collect::<Result<Vec<_>, HandlerError>>()?;
 
-#[derive(Serialize)]
-pub struct ChatPackage {
-    #[serde(with = "base64enc")]
-    identity_key: Vec<u8>,
-    #[serde(with = "base64enc")]
-    signed_prekey: Vec<u8>,
-    #[serde(with = "base64enc")]
-    prekey_signature: Vec<u8>,
-    #[serde(with = "base64enc")]
-    onetime_key: Vec<u8>
+    store.add_onetime_keys(user_id, &keys)
 }
 
-pub fn retrieve_package(pool: &Pool, user_id: Uuid) -> Result<ChatPackage, HandlerError> {
-    let conn = extract_connection(pool)?;
-    let (identity_key, signed_prekey, prekey_signature) = users::table.find(user_id).select((users::identity_key, users::signed_prekey, users::prekey_signature))
-        .first::<(Vec<u8>, Vec<u8>, Vec<u8>)>(&conn)
-        .map_err(|e| match e {
-            diesel::result::Error::NotFound => HandlerError::UnknownEntity { entity: Entity::User {uuid: user_id}},
-            _ => InternalError::DatabaseError(e).into()
-        })?;
-    let mut query = diesel::delete(onetimekeys::table.filter(
-        onetimekeys::id.eq(any(onetimekeys::table.select(onetimekeys::id)
-            .filter(onetimekeys::user_id.eq(user_id))
-            .limit(1).into_boxed()))))
-        .returning((onetimekeys::prekey))
-        .load::<Vec<u8>>(&conn).map_err(|e| HandlerError::InternalError {error: InternalError::DatabaseError(e)})?;
+pub fn retrieve_package(store: &dyn Storage, user_id: Uuid) -> Result<ChatPackage, HandlerError> {
+    let UserKeys { identity_key, identity_key_algorithm, signed_prekey, prekey_signature } = store.user_keys(user_id)?;
 
-    if query.len() == 0 {
-        return Err(HandlerError::InsufficientPrekeys)
-    }
+    let onetime_key = match store.take_onetime_key(user_id)? {
+        Some(key) => key,
+        None => {
+            metrics::PREKEY_EXHAUSTION.inc();
+            return Err(HandlerError::InsufficientPrekeys)
+        }
+    };
 
     Ok(ChatPackage {
         identity_key,
+        identity_key_algorithm,
         signed_prekey,
         prekey_signature,
-        onetime_key: query.remove(0)
+        onetime_key
     })
-}
\ No newline at end of file
+}
+
+pub fn update_settings(store: &dyn Storage, settings: UserSettings, user_id: Uuid) -> Result<(), HandlerError> {
+    if let Some(read_receipts) = settings.read_receipts {
+        store.set_read_receipts(user_id, read_receipts)?;
+    }
+    Ok(())
+}
+
+
+// Replaces a user's identity key, e.g. after reinstalling, discarding prekeys signed by the old one.
+// Everyone the user has exchanged messages with is told the identity changed, so their clients can
+// warn that the safety number is different. Returns the number of users notified.
+pub fn reset_identity(store: &dyn Storage, reset: IdentityReset, user_id: Uuid) -> Result<usize, HandlerError> {
+    crypto::check_public_key(reset.identity_key_algorithm, &reset.identity_key)?;
+    check_signed_prekey(reset.identity_key_algorithm, &reset.identity_key, &reset.signed_prekey, &reset.prekey_signature)?;
+
+    let keys = UserKeys {
+        identity_key: reset.identity_key,
+        identity_key_algorithm: reset.identity_key_algorithm,
+        signed_prekey: reset.signed_prekey,
+        prekey_signature: reset.prekey_signature,
+    };
+    let correspondents = store.reset_identity(user_id, &keys, &|correspondents| correspondents.iter()
+        .map(|correspondent| message::system_message(*correspondent, user_id, "identity-changed"))
+        .collect())?;
+    Ok(correspondents.len())
+}
@@ -1,32 +1,37 @@
 use actix_web::{ResponseError, HttpResponse};
-use actix_web::http::StatusCode;
+use actix_web::dev::HttpResponseBuilder as ResponseBuilder;
+use actix_web::http::{header, StatusCode};
 use std::fmt;
-use uuid::Uuid;
 use actix_web::error::BlockingError;
-use serde::Serialize;
-use serde::export::Formatter;
-use ring::error::Unspecified;
+use std::fmt::Formatter;
+use crate::crypto::KeyAlgorithm;
+use beacon_protocol::v1::error::ApiError;
+pub use beacon_protocol::v1::error::Entity;
 
-#[derive(Debug, Serialize)]
-#[serde(tag = "type")]
+#[derive(Debug)]
 pub enum HandlerError {
     InsufficientPrekeys,
     SessionExpired,
     SessionInvalid,
-    UnknownEntity { #[serde(flatten)] entity: Entity },
+    UnknownEntity { entity: Entity },
     RecordMustBeUnique { name: String},
     AuthenticationError,
     SignatureMismatch,
+    MalformedKey { algorithm: KeyAlgorithm },
     MalformedHeader { name: String },
     MalformedBody { error_message: String },
-    InternalError { #[serde(skip_serializing)] error: InternalError },
-}
-
-#[derive(Debug, Serialize)]
-#[serde(tag = "entity")]
-pub enum Entity {
-    User{uuid: Uuid},
-    Device{uuid: Uuid},
+    UnknownMessageType { name: String },
+    ReservedMessageType { name: String },
+    InvalidPayload { message_type: String, error_message: String },
+    PayloadTooLarge { limit: usize },
+    UploadOffsetMismatch { expected: i64 },
+    IncompleteUpload { size: i64, received: i64 },
+    DigestMismatch,
+    RateLimited { retry_after: u64 },
+    ChallengeRequired,
+    ChallengeFailed,
+    UnknownPushProvider { name: String },
+    InternalError { error: InternalError },
 }
 
 impl fmt::Display for HandlerError {
@@ -41,24 +46,101 @@ impl ResponseError for HandlerError {
             HandlerError::SessionInvalid => StatusCode::UNAUTHORIZED,
             HandlerError::SessionExpired => StatusCode::UNAUTHORIZED,
             HandlerError::AuthenticationError => StatusCode::UNAUTHORIZED,
+            HandlerError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            HandlerError::UploadOffsetMismatch { .. } => StatusCode::CONFLICT,
+            HandlerError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            HandlerError::ChallengeRequired => StatusCode::FORBIDDEN,
+            HandlerError::ChallengeFailed => StatusCode::FORBIDDEN,
             HandlerError::InternalError{ .. } => StatusCode::INTERNAL_SERVER_ERROR,
             _ => StatusCode::BAD_REQUEST,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        println!("Error serving request: {} {:?}", self.status_code(), self);
-        HttpResponse::build(self.status_code())
-            .json(self)
+        self.response_builder()
+            .json(ApiError::from(self))
     }
 }
+
+impl HandlerError {
+    // Variant name, as used in the `type` field of the serialized error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HandlerError::InsufficientPrekeys => "InsufficientPrekeys",
+            HandlerError::SessionExpired => "SessionExpired",
+            HandlerError::SessionInvalid => "SessionInvalid",
+            HandlerError::UnknownEntity { .. } => "UnknownEntity",
+            HandlerError::RecordMustBeUnique { .. } => "RecordMustBeUnique",
+            HandlerError::AuthenticationError => "AuthenticationError",
+            HandlerError::SignatureMismatch => "SignatureMismatch",
+            HandlerError::MalformedKey { .. } => "MalformedKey",
+            HandlerError::MalformedHeader { .. } => "MalformedHeader",
+            HandlerError::MalformedBody { .. } => "MalformedBody",
+            HandlerError::UnknownMessageType { .. } => "UnknownMessageType",
+            HandlerError::ReservedMessageType { .. } => "ReservedMessageType",
+            HandlerError::InvalidPayload { .. } => "InvalidPayload",
+            HandlerError::PayloadTooLarge { .. } => "PayloadTooLarge",
+            HandlerError::UploadOffsetMismatch { .. } => "UploadOffsetMismatch",
+            HandlerError::IncompleteUpload { .. } => "IncompleteUpload",
+            HandlerError::DigestMismatch => "DigestMismatch",
+            HandlerError::RateLimited { .. } => "RateLimited",
+            HandlerError::ChallengeRequired => "ChallengeRequired",
+            HandlerError::ChallengeFailed => "ChallengeFailed",
+            HandlerError::UnknownPushProvider { .. } => "UnknownPushProvider",
+            HandlerError::InternalError { .. } => "InternalError",
+        }
+    }
+
+    // Status and headers of the response, whatever format the body is encoded in
+    pub fn response_builder(&self) -> ResponseBuilder {
+        let mut builder = HttpResponse::build(self.status_code());
+        if let HandlerError::RateLimited { retry_after } = self {
+            builder.header(header::RETRY_AFTER, retry_after.to_string());
+        }
+        builder
+    }
+}
+
+// The error as clients see it, without internal details
+impl From<&HandlerError> for ApiError {
+    fn from(e: &HandlerError) -> Self {
+        match e {
+            HandlerError::InsufficientPrekeys => ApiError::InsufficientPrekeys,
+            HandlerError::SessionExpired => ApiError::SessionExpired,
+            HandlerError::SessionInvalid => ApiError::SessionInvalid,
+            HandlerError::UnknownEntity { entity } => ApiError::UnknownEntity { entity: entity.clone() },
+            HandlerError::RecordMustBeUnique { name } => ApiError::RecordMustBeUnique { name: name.clone() },
+            HandlerError::AuthenticationError => ApiError::AuthenticationError,
+            HandlerError::SignatureMismatch => ApiError::SignatureMismatch,
+            HandlerError::MalformedKey { algorithm } => ApiError::MalformedKey { algorithm: *algorithm },
+            HandlerError::MalformedHeader { name } => ApiError::MalformedHeader { name: name.clone() },
+            HandlerError::MalformedBody { error_message } => ApiError::MalformedBody { error_message: error_message.clone() },
+            HandlerError::UnknownMessageType { name } => ApiError::UnknownMessageType { name: name.clone() },
+            HandlerError::ReservedMessageType { name } => ApiError::ReservedMessageType { name: name.clone() },
+            HandlerError::InvalidPayload { message_type, error_message } =>
+                ApiError::InvalidPayload { message_type: message_type.clone(), error_message: error_message.clone() },
+            HandlerError::PayloadTooLarge { limit } => ApiError::PayloadTooLarge { limit: *limit },
+            HandlerError::UploadOffsetMismatch { expected } => ApiError::UploadOffsetMismatch { expected: *expected },
+            HandlerError::IncompleteUpload { size, received } => ApiError::IncompleteUpload { size: *size, received: *received },
+            HandlerError::DigestMismatch => ApiError::DigestMismatch,
+            HandlerError::RateLimited { retry_after } => ApiError::RateLimited { retry_after: *retry_after },
+            HandlerError::ChallengeRequired => ApiError::ChallengeRequired,
+            HandlerError::ChallengeFailed => ApiError::ChallengeFailed,
+            HandlerError::UnknownPushProvider { name } => ApiError::UnknownPushProvider { name: name.clone() },
+            HandlerError::InternalError { .. } => ApiError::InternalError,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum InternalError {
     DatabaseError(diesel::result::Error),
     PoolError(r2d2::Error),
+    StorageError(std::io::Error),
     AsyncError,
     RNGError,
     ServerDataError,
+    SerializationError,
     JustAnError,
 }
 
@@ -68,15 +150,21 @@ impl From<InternalError> for HandlerError {
     }
 }
 
+impl From<diesel::result::Error> for HandlerError {
+    fn from(e: diesel::result::Error) -> Self {
+        InternalError::DatabaseError(e).into()
+    }
+}
+
 // Ludicrous syntactic sugar
 pub async fn block<F, I>(f: F) -> Result<I, HandlerError>
     where
         F: FnOnce() -> Result<I, HandlerError> + Send + 'static,
         I: Send + 'static,
 {
-    return actix_web::web::block(f).await
+    actix_web::web::block(f).await
         .map_err(|e| match e {
             BlockingError::Error(e) => e,
             BlockingError::Canceled => HandlerError::InternalError{ error: InternalError::AsyncError },
-        });
+        })
 }
\ No newline at end of file
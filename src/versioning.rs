@@ -0,0 +1,90 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
+use actix_web::http::{header, HeaderName, HeaderValue};
+use futures::future::{ok, Ready};
+use futures::Future;
+
+// Marks every response from a deprecated API version, saying when it will be removed
+// and what replaces it, so clients can warn their users before they break
+#[derive(Clone)]
+pub struct Deprecated {
+    // HTTP-date after which the version may no longer be served (RFC 8594)
+    sunset: Option<HeaderValue>,
+    successor: HeaderValue,
+}
+
+impl Deprecated {
+    // `successor` is the path prefix of the version replacing this one
+    pub fn new(sunset: Option<&str>, successor: &str) -> Self {
+        let sunset = sunset.map(|date| {
+            chrono::DateTime::parse_from_rfc2822(date).expect("Sunset must be an HTTP-date, e.g. Sat, 01 Jan 2022 00:00:00 GMT");
+            HeaderValue::from_str(date).expect("Sunset must be an HTTP-date")
+        });
+        let successor = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+            .expect("Successor must be a path");
+        Deprecated { sunset, successor }
+    }
+
+    pub fn from_env(name: &str, successor: &str) -> Self {
+        Deprecated::new(dotenv::var(name).ok().as_deref(), successor)
+    }
+}
+
+impl<S: 'static, B> Transform<S> for Deprecated
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = DeprecatedMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(DeprecatedMiddleware { service, deprecated: self.clone() })
+    }
+}
+
+pub struct DeprecatedMiddleware<S> {
+    service: S,
+    deprecated: Deprecated,
+}
+
+impl<S, B> Service for DeprecatedMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let Deprecated { sunset, successor } = self.deprecated.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            // Failed session checks and rate limits are error responses rather than errors,
+            // so they carry the headers too
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            headers.insert(HeaderName::from_static("deprecation"), HeaderValue::from_static("true"));
+            if let Some(sunset) = sunset {
+                headers.insert(HeaderName::from_static("sunset"), sunset);
+            }
+            headers.insert(header::LINK, successor);
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,205 @@
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, HttpMessage, Error};
+use actix_web::body::Body;
+use actix_web::dev::{Payload, PayloadStream, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::http::header::{q, Accept, Header};
+use actix_service::{Service, Transform};
+use futures::future::{ok, ready, Ready, LocalBoxFuture, FutureExt};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::future::Future;
+use crate::logging::{RequestId, REQUEST_ID_HEADER};
+use crate::utils::{HandlerError, InternalError};
+use beacon_protocol::base64enc;
+use beacon_protocol::v1::error::ErrorBody;
+
+pub const JSON: &str = "application/json";
+pub const MSGPACK: &str = "application/msgpack";
+
+// Encoding used for request and response bodies
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    fn of_mime(essence: &str) -> Option<Format> {
+        match essence {
+            JSON | "*/*" | "application/*" => Some(Format::Json),
+            MSGPACK | "application/x-msgpack" => Some(Format::MessagePack),
+            _ => None
+        }
+    }
+
+    // Format the client asked to receive: the one it prefers most, or JSON if it names neither
+    pub fn accepted<M: HttpMessage>(msg: &M) -> Format {
+        let mut accept = Accept::parse(msg).map(|accept| accept.0).unwrap_or_default();
+        // Stable, so types of equal quality stay in the client's order
+        accept.sort_by_key(|accepted| std::cmp::Reverse(accepted.quality));
+        accept.iter()
+            .filter(|accepted| accepted.quality > q(0))
+            .find_map(|accepted| Format::of_mime(accepted.item.essence_str()))
+            .unwrap_or(Format::Json)
+    }
+
+    // Format the client sent the body in
+    pub fn of_body(req: &HttpRequest) -> Format {
+        req.mime_type().ok().flatten()
+            .and_then(|mime| Format::of_mime(mime.essence_str()))
+            .unwrap_or(Format::Json)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => JSON,
+            Format::MessagePack => MSGPACK,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, HandlerError> {
+        match self {
+            Format::Json => serde_json::to_vec(value)
+                .map_err(|_e| InternalError::SerializationError.into()),
+            Format::MessagePack => base64enc::raw_bytes(|| rmp_serde::to_vec_named(value))
+                .map_err(|_e| InternalError::SerializationError.into()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, HandlerError> {
+        match self {
+            Format::Json => serde_json::from_slice(body)
+                .map_err(|e| HandlerError::MalformedBody { error_message: e.to_string() }),
+            Format::MessagePack => rmp_serde::from_read_ref(body)
+                .map_err(|e| HandlerError::MalformedBody { error_message: e.to_string() }),
+        }
+    }
+
+    pub fn respond<T: Serialize>(self, status: StatusCode, value: &T) -> Result<HttpResponse, HandlerError> {
+        Ok(HttpResponse::build(status)
+            .content_type(self.content_type())
+            .body(self.encode(value)?))
+    }
+
+    pub fn ok<T: Serialize>(self, value: &T) -> Result<HttpResponse, HandlerError> {
+        self.respond(StatusCode::OK, value)
+    }
+
+    pub fn error_response(self, error: &HandlerError, request_id: Option<RequestId>) -> HttpResponse {
+        // The flattened error serialises as a map of unknown length, which rmp-serde can't
+        // write, so the body goes through a JSON value whose length is known
+        let body = serde_json::to_value(ErrorBody { request_id: request_id.map(|r| r.0), error: error.into() })
+            .map_err(|_e| InternalError::SerializationError.into())
+            .and_then(|body| self.encode(&body))
+            .expect("HandlerError serialisation cannot fail");
+        let mut builder = error.response_builder();
+        if let Some(request_id) = request_id {
+            builder.header(REQUEST_ID_HEADER, request_id.header_value());
+        }
+        builder
+            .content_type(self.content_type())
+            .body(body)
+    }
+}
+
+impl FromRequest for Format {
+    type Error = HandlerError;
+    type Future = Ready<Result<Self, HandlerError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        ready(Ok(Format::accepted(req)))
+    }
+}
+
+// Request body in whichever format the Content-Type names, defaulting to JSON
+pub struct Wire<T>(pub T);
+
+impl<T> Wire<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Wire<T> {
+    type Error = HandlerError;
+    type Future = LocalBoxFuture<'static, Result<Self, HandlerError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload<PayloadStream>) -> Self::Future {
+        let format = Format::of_body(req);
+        web::Bytes::from_request(req, payload)
+            .map(move |body| {
+                let body = body.map_err(|e| HandlerError::MalformedBody { error_message: e.to_string() })?;
+                format.decode(&body).map(Wire)
+            })
+            .boxed_local()
+    }
+}
+
+// Re-encodes HandlerError responses in the format the client accepts, tagged with the request id
+pub struct NegotiateErrors;
+
+impl<S: 'static> Transform<S> for NegotiateErrors
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = NegotiateErrorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(NegotiateErrorsMiddleware { service })
+    }
+}
+
+pub struct NegotiateErrorsMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for NegotiateErrorsMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let format = Format::accepted(&req);
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            // Middleware answers with error responses rather than failing, so the
+            // request they came from is still here to encode them for
+            let res = fut.await?;
+            let request_id = res.request().extensions().get::<RequestId>().copied();
+            let encoded = res.response().error()
+                .and_then(|e| e.as_error::<HandlerError>())
+                .map(|he| format.error_response(he, request_id));
+            Ok(match encoded {
+                Some(mut response) => {
+                    // Keep headers set on the way out, such as the rotated session nonce
+                    for (name, value) in res.response().headers().iter() {
+                        if !response.headers().contains_key(name) && name != header::CONTENT_LENGTH {
+                            response.headers_mut().append(name.clone(), value.clone());
+                        }
+                    }
+                    res.into_response(response)
+                },
+                None => res
+            })
+        })
+    }
+}
@@ -0,0 +1,509 @@
+// End-to-end tests, running the App from the library over HTTP. Tests touching most
+// of the Storage trait run against each backend that needs no database server.
+// Requests go through beacon_client, so the SDK breaks the tests whenever it drifts
+// from the server's side of the protocol.
+use actix_web::http::{Method, StatusCode};
+use actix_web::test::{self, TestServer};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use beacon_client::{solve_challenge, Client, ClientError};
+use beacon_protocol::v1::attachment::AttachmentLimits;
+use beacon_protocol::v1::error::{ApiError, Entity};
+use beacon_protocol::base64enc;
+use beacon_protocol::crypto::KeyAlgorithm;
+use beacon_protocol::v1::message::{CheckMessagesResponse, SendMessageRequest, RECEIPT_TYPE};
+use beacon_protocol::v1::session::{Challenge, RegisterDeviceRequest};
+use beacon_server::{build_app, AppState};
+use beacon_server::blobstore::LocalBlobStore;
+use beacon_server::challenge::ChallengeConfig;
+use beacon_server::health::Readiness;
+use beacon_server::message_types::MessageTypeRegistry;
+use beacon_server::push::{MockProvider, PushProviders, WakeUp};
+use beacon_server::ratelimit::{BucketConfig, KeyBy, RateLimit};
+use beacon_server::storage::{MemoryStorage, SqliteStorage, Storage};
+use beacon_server::versioning::Deprecated;
+
+#[derive(Clone, Copy)]
+enum Backend {
+    Memory,
+    Sqlite,
+}
+
+impl Backend {
+    fn open(self) -> Arc<dyn Storage> {
+        match self {
+            Backend::Memory => Arc::new(MemoryStorage::new()),
+            // A new file for each test, since every pooled connection to :memory: would
+            // get a database of its own
+            Backend::Sqlite => {
+                let path = std::env::temp_dir().join(format!("beacon-test-{}.sqlite", Uuid::new_v4()));
+                let path = path.to_str().expect("Temporary directory is not UTF-8");
+                Arc::new(SqliteStorage::open(path).expect("Failed to open SQLite storage"))
+            },
+        }
+    }
+}
+
+// Runs each of the async fns named, which take a Backend, as a test against every backend
+macro_rules! storage_tests {
+    ($($name:ident),*) => {
+        mod memory {
+            $(#[actix_rt::test] async fn $name() { super::$name(super::Backend::Memory).await })*
+        }
+        mod sqlite {
+            $(#[actix_rt::test] async fn $name() { super::$name(super::Backend::Sqlite).await })*
+        }
+    };
+}
+
+storage_tests!(full_flow, onetime_keys_are_used_once, messages_wake_registered_devices, attachments_are_shared_by_messages);
+
+// Configured explicitly rather than from the environment, so a developer's .env
+// can't change what the tests exercise
+fn test_state(backend: Backend) -> AppState {
+    let rng = SystemRandom::new();
+    let blob_dir = std::env::temp_dir().join(format!("beacon-test-{}", Uuid::new_v4()));
+    let unlimited = BucketConfig { capacity: 1000.0, refill_rate: 1000.0 };
+    let challenge_key = hmac::Key::generate(hmac::HMAC_SHA256, &rng).expect("Failed to generate challenge key");
+    AppState {
+        storage: backend.open(),
+        store: Arc::new(LocalBlobStore::new(blob_dir).expect("Failed to create blob directory")),
+        limits: AttachmentLimits { max_size: 1024 * 1024, retention: 60 * 60, upload_timeout: 60 * 60 },
+        registry: actix_web::web::Data::new(MessageTypeRegistry::default()),
+        push: actix_web::web::Data::new(PushProviders::empty()),
+        // Low enough to solve instantly, but still exercised
+        challenges: actix_web::web::Data::new(ChallengeConfig::new(4, 60, challenge_key)),
+        readiness: actix_web::web::Data::new(Readiness::new()),
+        anonymous_limit: RateLimit::new(unlimited, KeyBy::Ip { trusted_proxies: Vec::new() }),
+        session_limit: RateLimit::new(unlimited, KeyBy::Session),
+        legacy_api: Deprecated::new(Some(SUNSET), "/v1"),
+        admin_port: None,
+        rng,
+    }
+}
+
+const SUNSET: &str = "Sat, 01 Jan 2022 00:00:00 GMT";
+
+fn start_server(backend: Backend) -> TestServer {
+    let state = test_state(backend);
+    test::start(move || build_app(state.clone()))
+}
+
+fn generate_pkcs8() -> Vec<u8> {
+    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).expect("Failed to generate key").as_ref().to_vec()
+}
+
+fn key_from(pkcs8: &[u8]) -> Ed25519KeyPair {
+    Ed25519KeyPair::from_pkcs8(pkcs8).expect("Generated key is invalid")
+}
+
+fn new_key() -> Ed25519KeyPair {
+    key_from(&generate_pkcs8())
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new().fill(&mut bytes).expect("RNG failed");
+    bytes
+}
+
+// Registers a device and creates a user owning it
+async fn new_user(srv: &TestServer, email: &str, identity_key: &Ed25519KeyPair) -> (Client, Uuid) {
+    let mut client = Client::new(&srv.url("/"));
+    client.register_device(new_key()).await.expect("Failed to register device");
+    let user_id = client.create_user(email, identity_key, &random_bytes(32)).await.expect("Failed to create user");
+    (client, user_id)
+}
+
+// The API error a request failed with
+fn api_error<T>(result: Result<T, ClientError>) -> ApiError {
+    match result {
+        Err(e) => e.api_error().cloned().unwrap_or_else(|| panic!("Not an API error: {}", e)),
+        Ok(_) => panic!("Request unexpectedly succeeded"),
+    }
+}
+
+fn ciphertext(recipient: Uuid, body: &[u8]) -> SendMessageRequest {
+    SendMessageRequest {
+        recipient,
+        message_type: "ciphertext".to_string(),
+        payload: Some(json!({ "body": base64::encode(body) })),
+    }
+}
+
+async fn full_flow(backend: Backend) {
+    let srv = start_server(backend);
+    let bob_identity = new_key();
+    let (mut alice, alice_id) = new_user(&srv, "alice@example.com", &new_key()).await;
+    let (mut bob, bob_id) = new_user(&srv, "bob@example.com", &bob_identity).await;
+    let onetime_keys = vec![random_bytes(32), random_bytes(32)];
+    bob.upload_onetime_keys(&onetime_keys).await.unwrap();
+
+    let package = alice.fetch_package(bob_id).await.unwrap();
+    assert_eq!(package.identity_key, bob_identity.public_key().as_ref());
+    assert!(onetime_keys.contains(&package.onetime_key));
+
+    alice.send_message(&ciphertext(bob_id, b"hello bob")).await.unwrap();
+    alice.send_ciphertext(bob_id, "ciphertext", b"raw bytes").await.unwrap();
+    // A raw body has no room for the keys a prekey ciphertext carries
+    let error = api_error(alice.send_ciphertext(bob_id, "prekey-ciphertext", b"raw bytes").await);
+    assert!(matches!(error, ApiError::InvalidPayload { .. }));
+    let messages = bob.check_mailbox().await.unwrap();
+    assert_eq!(messages.len(), 2);
+    assert!(messages.iter().all(|message| message.sender == alice_id && message.message_type == "ciphertext"));
+    let json = messages.iter().find_map(|message| message.payload.as_ref()).expect("No JSON message");
+    assert_eq!(json["body"], base64::encode(b"hello bob"));
+    let binary = messages.iter().find_map(|message| message.ciphertext.as_ref()).expect("No binary message");
+    assert_eq!(binary, b"raw bytes");
+    assert!(bob.check_mailbox().await.unwrap().is_empty());
+
+    // Fetching the mailbox told Alice her messages were delivered
+    let receipts = alice.check_mailbox().await.unwrap();
+    assert_eq!(receipts.len(), 2);
+    for message in &messages {
+        assert!(receipts.iter().any(|receipt| receipt.message_type == RECEIPT_TYPE
+            && receipt.payload.as_ref().unwrap()["status"] == "delivered"
+            && receipt.payload.as_ref().unwrap()["message_id"] == message.id.to_string()));
+    }
+
+    // Only the first time a message is marked read sends a receipt
+    let message_ids: Vec<Uuid> = messages.iter().map(|message| message.id).collect();
+    bob.mark_read(message_ids.clone()).await.unwrap();
+    bob.mark_read(message_ids).await.unwrap();
+    let receipts = alice.check_mailbox().await.unwrap();
+    assert_eq!(receipts.len(), 2);
+    assert!(receipts.iter().all(|receipt| receipt.payload.as_ref().unwrap()["status"] == "read"));
+}
+
+async fn attachments_are_shared_by_messages(backend: Backend) {
+    let srv = start_server(backend);
+    let (mut alice, _) = new_user(&srv, "alice@example.com", &new_key()).await;
+    let (mut bob, bob_id) = new_user(&srv, "bob@example.com", &new_key()).await;
+    let (mut carol, _) = new_user(&srv, "carol@example.com", &new_key()).await;
+    let attachment_id = alice.upload_attachment(b"a picture").await.unwrap().attachment_id;
+    let unknown = |uuid| ApiError::UnknownEntity { entity: Entity::Attachment { uuid } };
+    let with_attachments = |recipient, attachments: Vec<Uuid>| SendMessageRequest {
+        recipient,
+        message_type: "ciphertext".to_string(),
+        payload: Some(json!({ "body": base64::encode(b"look"), "attachments": attachments })),
+    };
+
+    assert_eq!(alice.download_attachment(attachment_id).await.unwrap(), b"a picture");
+    assert_eq!(api_error(bob.download_attachment(attachment_id).await), unknown(attachment_id));
+
+    // Only the uploader can share an attachment, and only one which exists
+    let error = api_error(carol.send_message(&with_attachments(bob_id, vec![attachment_id])).await);
+    assert_eq!(error, unknown(attachment_id));
+    let missing = Uuid::new_v4();
+    assert_eq!(api_error(alice.send_message(&with_attachments(bob_id, vec![missing])).await), unknown(missing));
+
+    alice.send_message(&with_attachments(bob_id, vec![attachment_id])).await.unwrap();
+    assert_eq!(bob.download_attachment(attachment_id).await.unwrap(), b"a picture");
+    assert_eq!(api_error(carol.download_attachment(attachment_id).await), unknown(attachment_id));
+}
+
+async fn onetime_keys_are_used_once(backend: Backend) {
+    let srv = start_server(backend);
+    let (mut alice, _) = new_user(&srv, "alice@example.com", &new_key()).await;
+    let (mut bob, bob_id) = new_user(&srv, "bob@example.com", &new_key()).await;
+    bob.upload_onetime_keys(&[random_bytes(32)]).await.unwrap();
+
+    alice.fetch_package(bob_id).await.unwrap();
+    assert_eq!(api_error(alice.fetch_package(bob_id).await), ApiError::InsufficientPrekeys);
+}
+
+async fn open_session(srv: &TestServer) -> Vec<u8> {
+    let res = srv.post("/v1/session/new").send().await.expect("Request failed");
+    base64::decode(res.headers().get("x-newnonce").expect("No nonce").to_str().unwrap()).unwrap()
+}
+
+// An authenticated request in a new session, signed by hand for what the SDK can't send
+async fn signed_post(srv: &TestServer, device_id: Uuid, key: &Ed25519KeyPair, path: &str) -> awc::ClientRequest {
+    let nonce = open_session(srv).await;
+    srv.post(path)
+        .header("X-DEVICEID", device_id.to_string())
+        .header("X-NONCE", base64::encode(&nonce))
+        .header("X-SIGNEDNONCE", base64::encode(key.sign(&nonce).as_ref()))
+}
+
+// Sends the ciphertext as a MessagePack byte string, which the SDK's JSON payloads can't hold,
+// when encoded within base64enc::raw_bytes
+#[derive(Serialize)]
+struct BinaryPayloadMessage {
+    recipient: Uuid,
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    payload: BinaryCiphertext,
+}
+
+#[derive(Serialize)]
+struct BinaryCiphertext {
+    #[serde(with = "base64enc")]
+    body: Vec<u8>,
+}
+
+// Payload bytes MessagePack sends as binary reach recipients as base64, as JSON ones would
+#[actix_rt::test]
+async fn messagepack_payloads() {
+    let srv = start_server(Backend::Memory);
+    let (alice_key, bob_key) = (generate_pkcs8(), generate_pkcs8());
+    let mut alice = Client::new(&srv.url("/"));
+    let alice_device = alice.register_device(key_from(&alice_key)).await.unwrap();
+    alice.create_user("alice@example.com", &new_key(), &random_bytes(32)).await.unwrap();
+    let mut bob = Client::new(&srv.url("/"));
+    let bob_device = bob.register_device(key_from(&bob_key)).await.unwrap();
+    let bob_id = bob.create_user("bob@example.com", &new_key(), &random_bytes(32)).await.unwrap();
+
+    let message = BinaryPayloadMessage {
+        recipient: bob_id,
+        message_type: "ciphertext",
+        payload: BinaryCiphertext { body: b"hello bob".to_vec() },
+    };
+    let body = base64enc::raw_bytes(|| rmp_serde::to_vec_named(&message)).unwrap();
+    // "body" is followed by a bin 8 marker and the raw length, rather than a base64 string
+    let field = body.windows(5).position(|w| w == b"\xa4body").expect("No body field") + 5;
+    assert_eq!(body[field], 0xc4);
+    assert_eq!(body[field + 1] as usize, b"hello bob".len());
+    let res = signed_post(&srv, alice_device, &key_from(&alice_key), "/v1/messages/send").await
+        .content_type("application/msgpack")
+        .send_body(body)
+        .await.expect("Request failed");
+    assert!(res.status().is_success(), "Send returned {}", res.status());
+
+    let mut res = signed_post(&srv, bob_device, &key_from(&bob_key), "/v1/messages/mailbox").await
+        .header("accept", "application/msgpack")
+        .send().await.expect("Request failed");
+    assert!(res.status().is_success(), "Mailbox returned {}", res.status());
+    let body = res.body().await.expect("Failed to read body");
+    let CheckMessagesResponse { messages } = rmp_serde::from_read_ref(&body).expect("Mailbox is not MessagePack");
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].payload.as_ref().unwrap()["body"], base64::encode(b"hello bob"));
+}
+
+// The most preferred format is served, and one refused with q=0 never is
+#[actix_rt::test]
+async fn accept_qualities_are_honoured() {
+    let srv = start_server(Backend::Memory);
+    for (accept, expected) in [
+        ("application/msgpack;q=0, application/json", "application/json"),
+        ("application/json;q=0.5, application/msgpack", "application/msgpack"),
+        ("application/msgpack, application/json", "application/msgpack"),
+        ("application/msgpack;q=0", "application/json"),
+    ] {
+        let res = srv.post("/v1/messages/mailbox")
+            .header("accept", accept)
+            .send().await.expect("Request failed");
+        assert!(res.status().is_client_error());
+        let content_type = res.headers().get("content-type").expect("No content type").to_str().unwrap();
+        assert_eq!(content_type, expected, "Accept: {}", accept);
+    }
+}
+
+// The SDK never reuses a nonce, so this signs requests by hand with a copy of the device key
+#[actix_rt::test]
+async fn nonces_cannot_be_replayed() {
+    let srv = start_server(Backend::Memory);
+    let pkcs8 = generate_pkcs8();
+    let mut alice = Client::new(&srv.url("/"));
+    let device_id = alice.register_device(key_from(&pkcs8)).await.unwrap();
+
+    let res = srv.post("/v1/session/new").send().await.expect("Request failed");
+    let nonce = res.headers().get("x-newnonce").expect("No nonce").to_str().unwrap().to_string();
+    let signed_nonce = base64::encode(key_from(&pkcs8).sign(&base64::decode(&nonce).unwrap()).as_ref());
+    let mailbox = || srv.post("/v1/messages/mailbox")
+        .header("X-DEVICEID", device_id.to_string())
+        .header("X-NONCE", nonce.as_str())
+        .header("X-SIGNEDNONCE", signed_nonce.as_str())
+        .send();
+
+    let res = mailbox().await.expect("Request failed");
+    assert!(res.status().is_success());
+    let mut res = mailbox().await.expect("Request failed");
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let error: Value = res.json().await.expect("Error is not JSON");
+    assert_eq!(error["type"], "SessionInvalid");
+}
+
+// Anonymous routes are limited by address, which X-Forwarded-For can't change unless
+// the request came through a trusted proxy
+#[actix_rt::test]
+async fn spoofed_forwarding_headers_are_limited() {
+    let limit = BucketConfig { capacity: 2.0, refill_rate: 0.001 };
+    for (trusted_proxies, limited) in [(Vec::new(), true), (vec!["127.0.0.1".parse().unwrap()], false)] {
+        let mut state = test_state(Backend::Memory);
+        state.anonymous_limit = RateLimit::new(limit, KeyBy::Ip { trusted_proxies });
+        let srv = test::start(move || build_app(state.clone()));
+        let mut statuses = Vec::new();
+        for n in 0..3 {
+            let res = srv.post("/v1/session/new")
+                .header("x-forwarded-for", format!("203.0.113.{}", n))
+                .send().await.expect("Request failed");
+            statuses.push(res.status());
+        }
+        assert_eq!(statuses[2] == StatusCode::TOO_MANY_REQUESTS, limited, "Got {:?}", statuses);
+    }
+}
+
+// A solution is bound to the nonce it registered with, which registering uses up
+#[actix_rt::test]
+async fn challenges_register_one_device() {
+    let mut state = test_state(Backend::Memory);
+    // Hard enough that the solution fitting another nonce by chance is negligible
+    let challenge_key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).unwrap();
+    state.challenges = actix_web::web::Data::new(ChallengeConfig::new(16, 60, challenge_key));
+    let srv = test::start(move || build_app(state.clone()));
+
+    let mut res = srv.post("/v1/challenge/new").send().await.expect("Request failed");
+    let challenge: Challenge = res.json().await.expect("Challenge is not JSON");
+    let register = |nonce: Vec<u8>, solution: &[u8]| {
+        let key = new_key();
+        let request = RegisterDeviceRequest {
+            public_key: key.public_key().as_ref().to_vec(),
+            algorithm: KeyAlgorithm::Ed25519,
+            signed_nonce: key.sign(&nonce).as_ref().to_vec(),
+            nonce,
+        };
+        srv.post("/v1/devices/new")
+            .header("X-CHALLENGE", base64::encode(&challenge.challenge))
+            .header("X-CHALLENGE-SOLUTION", base64::encode(solution))
+            .send_json(&request)
+    };
+
+    let nonce = open_session(&srv).await;
+    let solution = solve_challenge(&challenge, &nonce);
+    let res = register(nonce, &solution).await.expect("Request failed");
+    assert!(res.status().is_success(), "Registration returned {}", res.status());
+
+    let mut res = register(open_session(&srv).await, &solution).await.expect("Request failed");
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let error: Value = res.json().await.expect("Error is not JSON");
+    assert_eq!(error["type"], "ChallengeFailed");
+}
+
+#[actix_rt::test]
+async fn legacy_paths_are_deprecated() {
+    let srv = start_server(Backend::Memory);
+    let res = srv.post("/session/new").send().await.expect("Request failed");
+    assert!(res.status().is_success());
+    assert_eq!(res.headers().get("deprecation").unwrap(), "true");
+    assert_eq!(res.headers().get("sunset").unwrap(), SUNSET);
+    assert_eq!(res.headers().get("link").unwrap(), "</v1>; rel=\"successor-version\"");
+
+    let res = srv.post("/v1/session/new").send().await.expect("Request failed");
+    assert!(res.status().is_success());
+    assert!(res.headers().get("deprecation").is_none());
+
+    // Requests failing authentication are still told the path is going away
+    let mut res = srv.post("/users/settings").send_json(&json!({ "read_receipts": false })).await.expect("Request failed");
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(res.headers().get("deprecation").unwrap(), "true");
+    assert_eq!(res.headers().get("sunset").unwrap(), SUNSET);
+    let error: Value = res.json().await.expect("Error is not JSON");
+    assert_eq!(error["type"], "MalformedHeader");
+}
+
+// Pushes are sent in the background, so may arrive after the request that caused them
+async fn wait_for_pushes(mock: &MockProvider, count: usize) -> Vec<(String, WakeUp)> {
+    for _ in 0..100 {
+        let sent = mock.sent();
+        if sent.len() >= count {
+            return sent;
+        }
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+    }
+    panic!("Expected {} pushes, got {:?}", count, mock.sent())
+}
+
+async fn messages_wake_registered_devices(backend: Backend) {
+    let mock = Arc::new(MockProvider::default());
+    let mut state = test_state(backend);
+    state.push = actix_web::web::Data::new(PushProviders::empty().register("mock", mock.clone()));
+    let srv = test::start(move || build_app(state.clone()));
+    let (mut alice, alice_id) = new_user(&srv, "alice@example.com", &new_key()).await;
+    let (mut bob, bob_id) = new_user(&srv, "bob@example.com", &new_key()).await;
+
+    let error = api_error(bob.register_push("carrier-pigeon", "bob").await);
+    assert_eq!(error, ApiError::UnknownPushProvider { name: "carrier-pigeon".to_string() });
+    bob.register_push("mock", "bob").await.unwrap();
+
+    alice.send_message(&ciphertext(bob_id, b"one")).await.unwrap();
+    alice.send_message(&ciphertext(bob_id, b"two")).await.unwrap();
+    let sent = wait_for_pushes(&mock, 2).await;
+    assert!(sent.iter().all(|(token, _)| token == "bob"));
+    assert_eq!(sent.iter().map(|(_, wake_up)| wake_up.missed_messages).max(), Some(2));
+
+    // Collecting the mailbox resets the count
+    assert_eq!(bob.check_mailbox().await.unwrap().len(), 2);
+    alice.send_message(&ciphertext(bob_id, b"three")).await.unwrap();
+    let sent = wait_for_pushes(&mock, 3).await;
+    assert_eq!(sent[2].1, WakeUp { missed_messages: 1 });
+
+    // Once unregistered, the device is left to poll
+    bob.unregister_push().await.unwrap();
+    alice.send_message(&ciphertext(bob_id, b"four")).await.unwrap();
+    actix_rt::time::delay_for(Duration::from_millis(100)).await;
+    assert_eq!(mock.sent().len(), 3);
+
+    // Alice has been sent receipts, which neither woke her nor count as missed
+    alice.register_push("mock", "alice").await.unwrap();
+    bob.send_message(&ciphertext(alice_id, b"hi alice")).await.unwrap();
+    let sent = wait_for_pushes(&mock, 4).await;
+    assert_eq!(sent[3], ("alice".to_string(), WakeUp { missed_messages: 1 }));
+}
+
+// Actix's own 404 and 405 have no body, unlike an UnknownEntity from a handler
+fn is_routed(result: Result<Vec<u8>, ClientError>) -> bool {
+    match result {
+        Ok(_) | Err(ClientError::Server { body: Some(_), .. }) => true,
+        Err(ClientError::Server { status, body: None }) =>
+            status != StatusCode::NOT_FOUND.as_u16() && status != StatusCode::METHOD_NOT_ALLOWED.as_u16(),
+        Err(e) => panic!("Request failed: {}", e),
+    }
+}
+
+// The API is routed from the OpenAPI operations, so check each has a handler and nothing else is served
+#[actix_rt::test]
+async fn openapi_matches_routes() {
+    let srv = start_server(Backend::Memory);
+    let mut res = srv.get("/v1/openapi.json").send().await.expect("Request failed");
+    assert!(res.status().is_success());
+    let spec: Value = res.json().limit(1 << 20).await.expect("Document is not JSON");
+    assert_eq!(spec["openapi"], "3.0.3");
+
+    let schemas = spec["components"]["schemas"].to_string();
+    let errors = ["InsufficientPrekeys", "SessionExpired", "SessionInvalid", "UnknownEntity", "RecordMustBeUnique",
+        "AuthenticationError", "SignatureMismatch", "MalformedKey", "MalformedHeader", "MalformedBody",
+        "UnknownMessageType", "ReservedMessageType", "InvalidPayload", "PayloadTooLarge", "UploadOffsetMismatch",
+        "IncompleteUpload", "DigestMismatch", "RateLimited", "ChallengeRequired", "ChallengeFailed", "UnknownPushProvider", "InternalError"];
+    for error in errors.iter() {
+        assert!(schemas.contains(&format!("\"{}\"", error)), "{} is not documented", error);
+    }
+
+    // Only needed when the server asks for proof of work
+    assert_eq!(spec["components"]["parameters"]["Challenge"]["required"], false);
+    assert_eq!(spec["components"]["parameters"]["ChallengeSolution"]["required"], false);
+
+    let (mut alice, _) = new_user(&srv, "alice@example.com", &new_key()).await;
+    assert!(!is_routed(alice.request(Method::POST, "/keys/undocumented").await));
+    assert!(!is_routed(alice.request(Method::GET, "/keys/signed").await));
+    for (path, operations) in spec["paths"].as_object().expect("No paths") {
+        let path: Vec<String> = path.split('/')
+            .map(|segment| match segment.starts_with('{') {
+                true => Uuid::new_v4().to_string(),
+                false => segment.to_string(),
+            })
+            .collect();
+        let path = path.join("/");
+        for method in operations.as_object().expect("No operations").keys() {
+            let method = Method::from_bytes(method.to_uppercase().as_bytes()).expect("Invalid method");
+            let result = alice.request(method.clone(), &path).await;
+            assert!(is_routed(result), "{} {} is documented but not routed", method, path);
+        }
+    }
+}